@@ -0,0 +1,21 @@
+extern crate backtalk;
+use backtalk::*;
+extern crate futures;
+
+fn main() {
+  let mut server = Server::new();
+  let cats = Resource::new(memory::MemoryAdapter::new())
+    .channel(memory::MemoryChannel::new())
+    .before(&[Method::Delete], |req: Request| {
+      if let &JsonValue::String(ref password) = req.param("password") {
+        if password != "meow" {
+          return Error::forbidden("incorrect password");
+        }
+      } else {
+        return Error::unauthorized("please provide a password");
+      }
+      req.boxed()
+    });
+  server.resource("/cats", cats);
+  server.listen("127.0.0.1:3000");
+}
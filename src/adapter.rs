@@ -1,17 +1,21 @@
-use {JsonObject, Request, Reply, Method, ErrorKind, Error};
+use {JsonObject, Request, Reply, Method, ErrorKind, Error, Channel};
 use futures::{BoxFuture, Future};
+use futures::future::err;
 use serde_json::Value as JsonValue;
+use std::sync::Arc;
 
 /**
 Converts a Request to a static Reply from a database.
 
 An `Adapter` talks to a database. If you're using an `Adapter`, and not implementing your own, you
 probably just want to use the `handle` function. By implementing the five functions `list`, `get`,
-`post`, `patch` and `delete`, an `Adapter` gets the `handle` function for free.
+`post`, `patch` and `delete`, an `Adapter` gets the `handle` function for free. Adapters that can
+replace objects at a client-chosen ID should also implement `update`.
 
 You most likely won't want to implement your own Adapter, since these are generic and don't contain
-project-specific code. Backtalk implements `memory::MemoryAdapter` for development, but you will
-hopefully eventually be able to find third-party adapters for various databases in other crates.
+project-specific code. Backtalk implements `memory::MemoryAdapter` for development, and
+`sqlite::SqliteAdapter` with the `sqlite` feature, but you will hopefully eventually be able to find
+third-party adapters for various databases in other crates.
 */
 
 pub trait Adapter: Send + Sync {
@@ -21,6 +25,41 @@ pub trait Adapter: Send + Sync {
   fn patch(&self, id: &str, data: &JsonObject, params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)>;
   fn delete(&self, id: &str, params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)>;
 
+  /**
+  Applies a list of JSON Patch operations to the object at `id`, and returns the patched object.
+  If any operation fails, none of them should be applied. By default, adapters don't support JSON
+  Patch, and return a `BadRequest` error. See the `patch` module.
+  */
+  fn json_patch(&self, id: &str, ops: &[JsonValue], params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let _ = (id, ops, params);
+    err((ErrorKind::BadRequest, json!({
+      "error": {
+        "type": ErrorKind::BadRequest.as_string(),
+        "message": "this adapter doesn't support JSON Patch",
+      }
+    }))).boxed()
+  }
+
+  /**
+  Replaces the object at `id` with `data`, creating it if it doesn't exist, and returns the new
+  object. `data` may leave out the ID, but if it has one it must match `id`. By default, adapters
+  don't support replacing objects, and return a `MethodNotAllowed` error.
+  */
+  fn update(&self, id: &str, data: &JsonObject, params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let _ = (id, data, params);
+    err((ErrorKind::MethodNotAllowed, json!({
+      "error": {
+        "type": ErrorKind::MethodNotAllowed.as_string(),
+        "message": "this adapter doesn't support replacing objects",
+      }
+    }))).boxed()
+  }
+
+  /// The field objects keep their id in, which events about new objects are sent by. Defaults to `"id"`.
+  fn id_field(&self) -> String {
+    "id".to_string()
+  }
+
   /**
   Takes a `Request`, passes it to the appropriate function, and turns the response into a proper
   `Reply` future. If you're using an `Adapter` in your webapp, this is the function you want to
@@ -32,7 +71,11 @@ pub trait Adapter: Send + Sync {
       (Method::Post, _) => self.post(req.data(), req.params()),
       (Method::Get, Some(ref id)) => self.get(id, req.params()),
       (Method::Delete, Some(ref id)) => self.delete(id, req.params()),
-      (Method::Patch, Some(ref id)) => self.patch(id, req.data(), req.params()),
+      (Method::Patch, Some(ref id)) => match req.json_patch() {
+        Some(ops) => self.json_patch(id, ops, req.params()),
+        None => self.patch(id, req.data(), req.params()),
+      },
+      (Method::Put, Some(ref id)) => self.update(id, req.data(), req.params()),
       (_, None) => return Error::bad_request("missing id in request"),
       (Method::Listen, _) => return Error::server_error("passed listen request to database adapter"),
       (Method::Action(_), _) => return Error::server_error("passed action request to database adapter"),
@@ -42,11 +85,97 @@ pub trait Adapter: Send + Sync {
       Err((kind, val)) => Err(Error::new(kind, val)),
     }).boxed()
   }
+
+  /**
+  Binds this adapter to a `Channel`, so every successful `post`, `patch`, `update` and `delete` is
+  sent to the channel as a `"created"`, `"patched"`, `"updated"` or `"removed"` event, with the
+  resulting object. Events go to clients listening to the object's id, and to clients listening to
+  the whole collection.
+
+  To also use the channel for `Listen` requests, pass in an `Arc` of it and keep a clone.
+  */
+  fn publish_to<C: Channel + 'static>(self, channel: C) -> Published<Self, C> where Self: Sized {
+    Published {
+      adapter: self,
+      channel: Arc::new(channel),
+    }
+  }
+}
+
+/**
+An `Adapter` that sends its changes to a `Channel`, created by `Adapter::publish_to`.
+*/
+pub struct Published<A, C> {
+  adapter: A,
+  channel: Arc<C>,
+}
+
+impl<A: Adapter, C: Channel + 'static> Adapter for Published<A, C> {
+  fn list(&self, params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    self.adapter.list(params)
+  }
+
+  fn get(&self, id: &str, params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    self.adapter.get(id, params)
+  }
+
+  fn post(&self, data: &JsonObject, params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let channel = self.channel.clone();
+    let id_field = self.adapter.id_field();
+    self.adapter.post(data, params).map(move |obj| {
+      match obj.get(&id_field) {
+        Some(&JsonValue::String(ref id)) => channel.send_about(id, "created", &obj),
+        _ => channel.send("created", &obj),
+      }
+      obj
+    }).boxed()
+  }
+
+  fn patch(&self, id: &str, data: &JsonObject, params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let channel = self.channel.clone();
+    let id = id.to_string();
+    self.adapter.patch(&id, data, params).map(move |obj| {
+      channel.send_about(&id, "patched", &obj);
+      obj
+    }).boxed()
+  }
+
+  fn json_patch(&self, id: &str, ops: &[JsonValue], params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let channel = self.channel.clone();
+    let id = id.to_string();
+    self.adapter.json_patch(&id, ops, params).map(move |obj| {
+      channel.send_about(&id, "patched", &obj);
+      obj
+    }).boxed()
+  }
+
+  fn update(&self, id: &str, data: &JsonObject, params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let channel = self.channel.clone();
+    let id = id.to_string();
+    self.adapter.update(&id, data, params).map(move |obj| {
+      channel.send_about(&id, "updated", &obj);
+      obj
+    }).boxed()
+  }
+
+  fn delete(&self, id: &str, params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let channel = self.channel.clone();
+    let id = id.to_string();
+    self.adapter.delete(&id, params).map(move |obj| {
+      channel.send_about(&id, "removed", &obj);
+      obj
+    }).boxed()
+  }
+
+  fn id_field(&self) -> String {
+    self.adapter.id_field()
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use futures::Stream;
   use futures::future::{ok, err};
   struct TestAdapter;
 
@@ -121,4 +250,57 @@ mod tests {
     let adapter = TestAdapter{};
     let _res = adapter.handle(make_req(Method::Post, None)).wait().unwrap_err();
   }
+
+  struct RecordingChannel {
+    sent: ::std::sync::Mutex<Vec<(String, String)>>,
+  }
+
+  impl Channel for RecordingChannel {
+    fn join(&self, _: ::Sender, _: Option<String>, _: JsonObject) {}
+    fn send(&self, _: &str, _: &JsonObject) {
+      panic!("published without an id");
+    }
+    fn send_about(&self, id: &str, event_type: &str, _: &JsonObject) {
+      self.sent.lock().unwrap().push((id.to_string(), event_type.to_string()));
+    }
+  }
+
+  #[test]
+  fn published_adapter_sends_changes() {
+    let channel = Arc::new(RecordingChannel { sent: ::std::sync::Mutex::new(Vec::new()) });
+    let adapter = ::memory::MemoryAdapter::new().publish_to(channel.clone());
+    let cat = adapter.handle(Request::new("/cats".to_string(), Method::Post, None, JsonObject::new(), JsonObject::new())).wait().unwrap();
+    let id = cat.data().unwrap().get("id").unwrap().as_str().unwrap().to_string();
+    adapter.handle(make_req(Method::Patch, Some(&id))).wait().unwrap();
+    adapter.handle(make_req(Method::Get, Some(&id))).wait().unwrap();
+    adapter.handle(make_req(Method::Delete, Some(&id))).wait().unwrap();
+    adapter.handle(make_req(Method::Patch, Some("nope"))).wait().unwrap_err();
+    let sent = channel.sent.lock().unwrap();
+    assert_eq!(*sent, vec![
+      (id.clone(), "created".to_string()),
+      (id.clone(), "patched".to_string()),
+      (id.clone(), "removed".to_string()),
+    ]);
+  }
+
+  fn listen(chan: &Arc<::memory::MemoryChannel>, id: &str) -> ::reply::EventReceiver {
+    let req = Request::new("/cats".to_string(), Method::Listen, Some(id.to_string()), JsonObject::new(), JsonObject::new());
+    ::reply::take_event_stream(chan.handle(req).wait().unwrap()).ok().unwrap()
+  }
+
+  #[test]
+  fn publishes_new_objects_by_their_id_field() {
+    let chan = Arc::new(::memory::MemoryChannel::new());
+    let first = listen(&chan, "1");
+    let second = listen(&chan, "2");
+    let post = || Request::new("/cats".to_string(), Method::Post, None, JsonObject::new(), JsonObject::new());
+    let adapter = ::memory::MemoryAdapter::new().with_id_field("_id").publish_to(chan.clone());
+    adapter.handle(post()).wait().unwrap();
+    let resource = ::Resource::new(::memory::MemoryAdapter::new().with_id_field("_id")).channel(chan.clone());
+    ::Handler::handle(&resource, post()).wait().unwrap();
+    drop((adapter, resource, chan));
+    let events = |stream: ::reply::EventReceiver| stream.map(|(_, event, _)| event).collect().wait().unwrap();
+    assert_eq!(events(first), vec!["created", "created"]);
+    assert_eq!(events(second), Vec::<String>::new());
+  }
 }
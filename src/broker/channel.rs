@@ -0,0 +1,143 @@
+use {Channel, Sender, JsonObject, Error, Overflow, StreamOptions, Request, Reply};
+use memory::MemoryChannel;
+use super::{Broker, Message};
+use futures::BoxFuture;
+use std::sync::Arc;
+
+/**
+A `Channel` that relays the messages sent to it through a `Broker`, so they reach clients listening
+to any channel subscribed to the same broker, even in other processes.
+
+Clients join a local channel, a `MemoryChannel` by default, which sends them the messages coming
+from the broker. Everything other than sending, like authorization, presence and queue limits, is
+left to the local channel, so presence only covers clients connected to this process. Event IDs are
+also given out by each local channel, so a client reconnecting with a `Last-Event-ID` should come
+back to the same process to get sent the messages it missed.
+
+```rust,no_run
+# extern crate backtalk;
+# use backtalk::*;
+# use backtalk::broker::{BrokerChannel, TcpBroker};
+# fn main() {
+// one process serves the broker, and the others connect to it
+let broker = TcpBroker::serve("127.0.0.1:3001").unwrap();
+let chan = BrokerChannel::new(broker);
+
+let broker = TcpBroker::connect("127.0.0.1:3001").unwrap();
+let other_chan = BrokerChannel::new(broker);
+# }
+```
+*/
+pub struct BrokerChannel {
+  local: Arc<Channel>,
+  broker: Box<Broker>,
+}
+
+impl BrokerChannel {
+  /// Creates a channel that relays messages through `broker` to a `MemoryChannel`.
+  pub fn new<B: Broker + 'static>(broker: B) -> BrokerChannel {
+    BrokerChannel::with_channel(broker, MemoryChannel::new())
+  }
+
+  /// Creates a channel that relays messages through `broker` to `local`.
+  pub fn with_channel<B, C>(broker: B, local: C) -> BrokerChannel
+    where B: Broker + 'static, C: Channel + 'static
+  {
+    let local: Arc<Channel> = Arc::new(local);
+    let subscribed = Arc::downgrade(&local);
+    broker.subscribe(Box::new(move |msg: &Message| {
+      match subscribed.upgrade() {
+        Some(local) => {
+          match msg.about {
+            Some(ref id) => local.send_about(id, &msg.event, &msg.data),
+            None => local.send(&msg.event, &msg.data),
+          }
+          true
+        },
+        None => false,
+      }
+    }));
+    BrokerChannel {
+      local: local,
+      broker: Box::new(broker),
+    }
+  }
+}
+
+impl Channel for BrokerChannel {
+  fn join(&self, sender: Sender, id: Option<String>, params: JsonObject) {
+    self.local.join(sender, id, params)
+  }
+
+  fn authorize(&self, id: &Option<String>, params: &JsonObject) -> BoxFuture<(), Error> {
+    self.local.authorize(id, params)
+  }
+
+  fn rejoin(&self, sender: Sender, id: Option<String>, params: JsonObject, last_event_id: &str) {
+    self.local.rejoin(sender, id, params, last_event_id)
+  }
+
+  fn presence(&self, id: &Option<String>) -> Vec<JsonObject> {
+    self.local.presence(id)
+  }
+
+  fn send(&self, event_type: &str, msg: &JsonObject) {
+    self.broker.publish(Message {
+      about: None,
+      event: event_type.to_string(),
+      data: msg.clone(),
+    });
+  }
+
+  fn send_about(&self, id: &str, event_type: &str, msg: &JsonObject) {
+    self.broker.publish(Message {
+      about: Some(id.to_string()),
+      event: event_type.to_string(),
+      data: msg.clone(),
+    });
+  }
+
+  fn queue_limit(&self) -> (usize, Overflow) {
+    self.local.queue_limit()
+  }
+
+  fn stream_options(&self) -> StreamOptions {
+    self.local.stream_options()
+  }
+
+  fn handle(&self, req: Request) -> BoxFuture<Reply, Error> {
+    self.local.handle(req)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use obj;
+  use {Request, Method};
+  use broker::LocalBroker;
+  use reply::{take_event_stream, EventReceiver};
+  use futures::{Future, Stream};
+
+  fn listen(chan: &BrokerChannel, id: Option<&str>) -> EventReceiver {
+    let req = Request::new("/cats".to_string(), Method::Listen, id.map(|s| s.to_string()), JsonObject::new(), JsonObject::new());
+    take_event_stream(chan.handle(req).wait().unwrap()).ok().unwrap()
+  }
+
+  #[test]
+  fn relays_messages_between_channels() {
+    let broker = LocalBroker::new();
+    let first = BrokerChannel::new(broker.clone());
+    let second = BrokerChannel::new(broker.clone());
+    let all = listen(&first, None);
+    let one = listen(&second, Some("1"));
+    first.send_about("1", "patched", &obj(json!({"id": "1"})));
+    second.send("reset", &obj(json!({"id": "all"})));
+    drop(first);
+    drop(second);
+    let events: Vec<Vec<String>> = vec![all, one].into_iter().map(|stream| {
+      stream.map(|(_, event, _)| event).collect().wait().unwrap()
+    }).collect();
+    assert_eq!(events, vec![vec!["patched", "reset"], vec!["patched", "reset"]]);
+  }
+}
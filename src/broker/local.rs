@@ -0,0 +1,36 @@
+use super::{Broker, Message};
+use std::sync::{Arc, Mutex};
+
+type Subscriber = Arc<Fn(&Message) -> bool + Send + Sync>;
+
+/**
+A `Broker` that passes messages between channels in the same process. Clones share the same
+subscribers.
+*/
+#[derive(Clone)]
+pub struct LocalBroker {
+  subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl LocalBroker {
+  pub fn new() -> LocalBroker {
+    LocalBroker {
+      subscribers: Arc::new(Mutex::new(Vec::new())),
+    }
+  }
+}
+
+impl Broker for LocalBroker {
+  fn publish(&self, msg: Message) {
+    // subscribers are called without the lock, so they can publish or subscribe themselves
+    let subscribers = self.subscribers.lock().unwrap().clone();
+    let gone: Vec<Subscriber> = subscribers.into_iter().filter(|s| !s(&msg)).collect();
+    if !gone.is_empty() {
+      self.subscribers.lock().unwrap().retain(|s| !gone.iter().any(|g| Arc::ptr_eq(s, g)));
+    }
+  }
+
+  fn subscribe(&self, subscriber: Box<Fn(&Message) -> bool + Send + Sync>) {
+    self.subscribers.lock().unwrap().push(Arc::from(subscriber));
+  }
+}
@@ -0,0 +1,41 @@
+/*!
+Channels that reach clients connected to other processes, by relaying messages through a broker.
+
+A `BrokerChannel` hands every message sent to it to a `Broker`, which passes it on to every
+`BrokerChannel` subscribed to that broker, in this process or another one. Each of those then sends
+it to its own clients. `LocalBroker` only reaches channels in the same process, which is handy for
+tests, and `TcpBroker` lets several processes on the same machine (or network) share messages.
+*/
+
+use JsonObject;
+
+mod channel;
+pub use self::channel::BrokerChannel;
+
+mod local;
+pub use self::local::LocalBroker;
+
+mod tcp;
+pub use self::tcp::TcpBroker;
+
+/// A message relayed through a `Broker`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+  /// The ID the message is about, or `None` if it goes to everyone listening.
+  pub about: Option<String>,
+  pub event: String,
+  pub data: JsonObject,
+}
+
+/**
+Passes messages between channels, which may live in other processes.
+
+Subscribers get every message published through the broker, including their own. A subscriber
+returns `false` once it doesn't want any more messages, for instance because its channel was
+dropped, and the broker forgets about it.
+*/
+pub trait Broker: Send + Sync {
+  fn publish(&self, msg: Message);
+
+  fn subscribe(&self, subscriber: Box<Fn(&Message) -> bool + Send + Sync>);
+}
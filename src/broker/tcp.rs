@@ -0,0 +1,315 @@
+use {JsonValue, JsonObject};
+use super::{Broker, Message, LocalBroker};
+use serde_json;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, SocketAddr, ToSocketAddrs, Shutdown, Ipv4Addr, Ipv6Addr, IpAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+
+// how many lines can be waiting to be written to a peer before it's dropped for being too slow
+const PEER_QUEUE_LIMIT: usize = 1000;
+// the longest line a peer can send, so a broken peer can't use up all the memory
+const MAX_LINE_LEN: usize = 1024 * 1024;
+
+// a process this one is connected to, with an id to tell them apart, and the queue its writer
+// thread takes lines from
+struct Peer {
+  id: usize,
+  queue: SyncSender<Arc<String>>,
+  stream: TcpStream,
+}
+
+struct Inner {
+  addr: SocketAddr,
+  local: LocalBroker,
+  peers: Mutex<Vec<Peer>>,
+}
+
+impl Drop for Inner {
+  // disconnects every peer, which ends their threads, and wakes up the accepting thread so it ends too
+  fn drop(&mut self) {
+    for peer in self.peers.lock().unwrap().drain(..) {
+      let _ = peer.stream.shutdown(Shutdown::Both);
+    }
+    let mut addr = self.addr;
+    match addr.ip() {
+      IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))),
+      IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))),
+      _ => (),
+    }
+    let _ = TcpStream::connect(addr);
+  }
+}
+
+/**
+A `Broker` that passes messages between processes over TCP.
+
+One process calls `TcpBroker::serve` to start the broker, which relays every message it gets to all
+the other processes, and the others call `TcpBroker::connect` to join it. Messages are sent as lines
+of JSON. Each connection has a thread reading from it and a thread writing to it, so a slow process
+never holds up the one sending a message; a process that falls too far behind is disconnected.
+Clones share the same connections, which are closed once every clone is dropped.
+
+If a process loses its connection to the broker, it stops getting messages from the other
+processes, but keeps working on its own.
+*/
+#[derive(Clone)]
+pub struct TcpBroker {
+  inner: Arc<Inner>,
+}
+
+impl TcpBroker {
+  fn new(addr: SocketAddr) -> TcpBroker {
+    TcpBroker {
+      inner: Arc::new(Inner {
+        addr: addr,
+        local: LocalBroker::new(),
+        peers: Mutex::new(Vec::new()),
+      }),
+    }
+  }
+
+  /// Starts a broker listening on `addr`, which other processes can connect to.
+  pub fn serve<A: ToSocketAddrs>(addr: A) -> io::Result<TcpBroker> {
+    let listener = TcpListener::bind(addr)?;
+    let broker = TcpBroker::new(listener.local_addr()?);
+    let accepting = Arc::downgrade(&broker.inner);
+    thread::Builder::new().name("backtalk-broker".to_string()).spawn(move || {
+      for (peer_id, stream) in listener.incoming().enumerate() {
+        let inner = match accepting.upgrade() {
+          Some(inner) => inner,
+          None => break,
+        };
+        if let Ok(stream) = stream {
+          let _ = add_peer(&inner, peer_id, stream);
+        }
+      }
+    })?;
+    Ok(broker)
+  }
+
+  /// Connects to a broker that another process started with `TcpBroker::serve`.
+  pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpBroker> {
+    let stream = TcpStream::connect(addr)?;
+    let broker = TcpBroker::new(stream.peer_addr()?);
+    add_peer(&broker.inner, 0, stream)?;
+    Ok(broker)
+  }
+
+  /// The address of the broker, which other processes can connect to.
+  pub fn addr(&self) -> SocketAddr {
+    self.inner.addr
+  }
+}
+
+// starts the threads that write queued lines to the peer, and that pass on messages from the peer
+// to subscribers and all the other peers
+fn add_peer(inner: &Arc<Inner>, peer_id: usize, stream: TcpStream) -> io::Result<()> {
+  let mut reader = BufReader::new(stream.try_clone()?);
+  let mut writer = stream.try_clone()?;
+  let (queue, lines) = mpsc::sync_channel::<Arc<String>>(PEER_QUEUE_LIMIT);
+  thread::Builder::new().name("backtalk-broker-writer".to_string()).spawn(move || {
+    // ends once the peer is dropped, which drops the other end of the queue
+    for line in lines {
+      if writeln!(writer, "{}", line).is_err() {
+        break;
+      }
+    }
+    let _ = writer.shutdown(Shutdown::Both);
+  })?;
+  inner.peers.lock().unwrap().push(Peer {
+    id: peer_id,
+    queue: queue,
+    stream: stream,
+  });
+  let weak = Arc::downgrade(inner);
+  thread::Builder::new().name("backtalk-broker-reader".to_string()).spawn(move || {
+    while let Some(line) = read_line(&mut reader) {
+      let inner = match weak.upgrade() {
+        Some(inner) => inner,
+        None => return,
+      };
+      if let Some(msg) = decode(&line) {
+        relay(&inner, Some(peer_id), Arc::new(line));
+        inner.local.publish(msg);
+      }
+    }
+    if let Some(inner) = weak.upgrade() {
+      drop_peer(&mut inner.peers.lock().unwrap(), peer_id);
+    }
+  })?;
+  Ok(())
+}
+
+// reads a line from the peer, or `None` once it's disconnected or sends a line that's too long
+fn read_line<R: BufRead>(reader: &mut R) -> Option<String> {
+  let mut buf = Vec::new();
+  match reader.by_ref().take(MAX_LINE_LEN as u64 + 1).read_until(b'\n', &mut buf) {
+    Ok(0) | Err(_) => return None,
+    Ok(_) => (),
+  }
+  if buf.last() != Some(&b'\n') {
+    return None;
+  }
+  buf.pop();
+  String::from_utf8(buf).ok()
+}
+
+fn drop_peer(peers: &mut Vec<Peer>, peer_id: usize) {
+  peers.retain(|peer| {
+    if peer.id == peer_id {
+      let _ = peer.stream.shutdown(Shutdown::Both);
+    }
+    peer.id != peer_id
+  });
+}
+
+// queues the line for every peer except the one it came from, dropping the peers that are gone or
+// too far behind. this never blocks on the network.
+fn relay(inner: &Inner, from: Option<usize>, line: Arc<String>) {
+  let mut peers = inner.peers.lock().unwrap();
+  let mut gone = Vec::new();
+  for peer in peers.iter().filter(|peer| Some(peer.id) != from) {
+    match peer.queue.try_send(line.clone()) {
+      Ok(()) => (),
+      Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => gone.push(peer.id),
+    }
+  }
+  for peer_id in gone {
+    drop_peer(&mut peers, peer_id);
+  }
+}
+
+fn encode(msg: &Message) -> String {
+  json!({
+    "about": msg.about,
+    "event": msg.event,
+    "data": msg.data,
+  }).to_string()
+}
+
+fn decode(line: &str) -> Option<Message> {
+  let mut obj: JsonObject = match serde_json::from_str(line) {
+    Ok(obj) => obj,
+    Err(_) => return None,
+  };
+  let about = match obj.remove("about") {
+    Some(JsonValue::String(about)) => Some(about),
+    _ => None,
+  };
+  let event = match obj.remove("event") {
+    Some(JsonValue::String(event)) => event,
+    _ => return None,
+  };
+  let data = match obj.remove("data") {
+    Some(JsonValue::Object(data)) => data,
+    _ => return None,
+  };
+  Some(Message {
+    about: about,
+    event: event,
+    data: data,
+  })
+}
+
+impl Broker for TcpBroker {
+  fn publish(&self, msg: Message) {
+    relay(&self.inner, None, Arc::new(encode(&msg)));
+    self.inner.local.publish(msg);
+  }
+
+  fn subscribe(&self, subscriber: Box<Fn(&Message) -> bool + Send + Sync>) {
+    self.inner.local.subscribe(subscriber)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::mpsc;
+  use std::time::Duration;
+
+  fn received(broker: &TcpBroker) -> mpsc::Receiver<Message> {
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    broker.subscribe(Box::new(move |msg: &Message| tx.lock().unwrap().send(msg.clone()).is_ok()));
+    rx
+  }
+
+  fn message(about: Option<&str>, event: &str) -> Message {
+    let mut data = JsonObject::new();
+    data.insert("id".to_string(), json!("1"));
+    Message {
+      about: about.map(|s| s.to_string()),
+      event: event.to_string(),
+      data: data,
+    }
+  }
+
+  #[test]
+  fn relays_messages_between_processes() {
+    let hub = TcpBroker::serve("127.0.0.1:0").unwrap();
+    let first = TcpBroker::connect(hub.addr()).unwrap();
+    let second = TcpBroker::connect(hub.addr()).unwrap();
+    // the hub accepts connections on its own thread
+    while hub.inner.peers.lock().unwrap().len() < 2 {
+      thread::sleep(Duration::from_millis(10));
+    }
+    let (hub_rx, first_rx, second_rx) = (received(&hub), received(&first), received(&second));
+    let timeout = Duration::from_secs(5);
+
+    first.publish(message(Some("1"), "patched"));
+    for rx in &[&hub_rx, &first_rx, &second_rx] {
+      assert_eq!(rx.recv_timeout(timeout).unwrap(), message(Some("1"), "patched"));
+    }
+    hub.publish(message(None, "reset"));
+    for rx in &[&hub_rx, &first_rx, &second_rx] {
+      assert_eq!(rx.recv_timeout(timeout).unwrap(), message(None, "reset"));
+    }
+  }
+
+  // waits for the hub to have `n` peers, since it accepts and drops them on other threads
+  fn wait_for_peers(hub: &TcpBroker, n: usize) {
+    for _ in 0..500 {
+      if hub.inner.peers.lock().unwrap().len() == n {
+        return;
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+    panic!("the hub never had {} peers", n);
+  }
+
+  #[test]
+  fn drops_misbehaving_peers() {
+    let hub = TcpBroker::serve("127.0.0.1:0").unwrap();
+    let mut long = TcpStream::connect(hub.addr()).unwrap();
+    wait_for_peers(&hub, 1);
+    let _ = long.write_all(&vec![b'x'; MAX_LINE_LEN + 2]);
+    wait_for_peers(&hub, 0);
+
+    // a peer that never reads falls behind, and publishing doesn't wait for it
+    let _stalled = TcpStream::connect(hub.addr()).unwrap();
+    wait_for_peers(&hub, 1);
+    let mut msg = message(None, "big");
+    msg.data.insert("padding".to_string(), json!(String::from_utf8(vec![b'x'; 4096]).unwrap()));
+    for _ in 0..10000 {
+      hub.publish(msg.clone());
+      if hub.inner.peers.lock().unwrap().is_empty() {
+        break;
+      }
+    }
+    assert!(hub.inner.peers.lock().unwrap().is_empty());
+
+    // dropping the broker stops it accepting connections
+    let addr = hub.addr();
+    drop(hub);
+    for _ in 0..500 {
+      if TcpStream::connect(addr).is_err() {
+        return;
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+    panic!("the broker kept accepting connections");
+  }
+}
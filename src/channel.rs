@@ -1,11 +1,67 @@
 use {Request, Reply, Error, JsonObject, Method};
 use reply::make_streamed_reply;
-use futures::Future;
-use futures;
+use futures::{Future, Stream, Poll, Async};
 use futures::future::ok;
 use futures::future::BoxFuture;
+use futures::task::{self, Task};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-type ValueSender = futures::sync::mpsc::UnboundedSender<(String, JsonObject)>;
+// only used internally, the number of messages queued for a client by default, before the
+// `Overflow` policy kicks in
+pub const DEFAULT_QUEUE_LIMIT: usize = 1000;
+
+/**
+What a `Sender` does with a new message when its client's queue is full, because the client isn't
+reading messages as fast as they're sent.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+  /// Drops the oldest queued message to make room for the new one.
+  DropOldest,
+  /// Drops the new message.
+  DropNewest,
+  /// Drops all the queued messages and disconnects the client.
+  Disconnect,
+}
+
+/**
+Settings for the event streams a `Channel` sends to its clients. Settings left as `None` use the
+`Server`'s settings instead, which can be changed with `Server::heartbeat` and `Server::retry`.
+A `heartbeat` of zero turns heartbeats off for the channel even if the server sends them.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamOptions {
+  /// How often to send a `:keepalive` comment, so proxies don't close idle streams.
+  pub heartbeat: Option<Duration>,
+  /// How long clients should wait before reconnecting, sent as the stream's `retry:` field.
+  pub retry: Option<Duration>,
+}
+
+// only used internally, an event's id if it has one, its type and its data
+pub type Event = (Option<u64>, String, JsonObject);
+
+struct Queue {
+  messages: VecDeque<Event>,
+  limit: usize,
+  overflow: Overflow,
+  // set once the client disconnects, or the sender is dropped or disconnects the client
+  closed: bool,
+  // the task reading messages, if it's waiting for more
+  reader: Option<Task>,
+  // runs once the client disconnects
+  on_disconnect: Option<Box<FnOnce() + Send>>,
+}
+
+impl Queue {
+  fn close(&mut self) {
+    self.closed = true;
+    if let Some(task) = self.reader.take() {
+      task.notify();
+    }
+  }
+}
 
 /**
 Sends JSON objects over a realtime stream to a single connected client
@@ -14,14 +70,31 @@ You almost certainly will only use a `Sender` inside of a `Channel`.
 */
 
 pub struct Sender {
-  inner: ValueSender,
+  queue: Arc<Mutex<Queue>>,
+  filter: Option<Box<Fn(String, JsonObject) -> Option<(String, JsonObject)> + Send + Sync>>,
+}
+
+// only used internally, the stream of messages from a `Sender`
+pub struct Receiver {
+  queue: Arc<Mutex<Queue>>,
 }
 
 // only used internally
-pub fn new_sender(chunk_sender: ValueSender) -> Sender {
-  Sender {
-    inner: chunk_sender,
-  }
+pub fn new_queue(limit: usize, overflow: Overflow) -> (Sender, Receiver) {
+  assert!(limit > 0, "a client's queue needs room for at least one message");
+  let queue = Arc::new(Mutex::new(Queue {
+    messages: VecDeque::new(),
+    limit: limit,
+    overflow: overflow,
+    closed: false,
+    reader: None,
+    on_disconnect: None,
+  }));
+  let sender = Sender {
+    queue: queue.clone(),
+    filter: None,
+  };
+  (sender, Receiver { queue: queue })
 }
 
 impl Sender {
@@ -30,11 +103,130 @@ impl Sender {
 
   `event_type` is some sort of event type, like `"post"` or `"delete"`, but it can be whatever
   you'd like. The `Result` returned is `Ok(())` if the send was successful, and `Err(())` if the
-  send was a failure. Note that this function returns instantly; the messages are queued in memory
-  before being sent out to the client.
+  client has disconnected. Note that this function returns instantly; the messages are queued in
+  memory before being sent out to the client. If the client's queue is full, the `Channel`'s
+  `Overflow` policy decides what happens, and disconnecting the client returns `Err(())`.
   */
   pub fn send<S: Into<String>>(&mut self, event_type: S, val: JsonObject) -> Result<(), ()> {
-    self.inner.send((event_type.into(), val)).map_err(|_| ())
+    self.push((None, event_type.into(), val))
+  }
+
+  /**
+  Like `send`, but with an id for the event, which event-stream clients send back in the
+  `Last-Event-ID` header when they reconnect. Ids should increase with every event a `Channel`
+  sends, so the channel can tell which events the client missed.
+  */
+  pub fn send_with_id<S: Into<String>>(&mut self, id: u64, event_type: S, val: JsonObject) -> Result<(), ()> {
+    self.push((Some(id), event_type.into(), val))
+  }
+
+  /**
+  Runs `f` on the type and data of every event sent to this client, before it's queued. `f` can
+  change the event, for instance to remove private fields this client shouldn't see, or return
+  `None` to not send it at all. A `Channel` would usually call this in `join`, based on the params.
+  Calling this again runs the new `f` on the results of the previous ones.
+  */
+  pub fn filter_map<F>(&mut self, f: F)
+    where F: Fn(String, JsonObject) -> Option<(String, JsonObject)> + Send + Sync + 'static
+  {
+    self.filter = match self.filter.take() {
+      Some(prev) => Some(Box::new(move |event, val| prev(event, val).and_then(|(event, val)| f(event, val)))),
+      None => Some(Box::new(f)),
+    };
+  }
+
+  fn push(&mut self, event: Event) -> Result<(), ()> {
+    let event = match self.filter {
+      Some(ref f) => {
+        let (id, event_type, val) = event;
+        match f(event_type, val) {
+          Some((event_type, val)) => (id, event_type, val),
+          // suppressed events still fail for disconnected clients
+          None => return if self.is_closed() { Err(()) } else { Ok(()) },
+        }
+      },
+      None => event,
+    };
+    let mut queue = self.queue.lock().unwrap();
+    if queue.closed {
+      return Err(());
+    }
+    if queue.messages.len() >= queue.limit {
+      match queue.overflow {
+        Overflow::DropOldest => {
+          queue.messages.pop_front();
+        },
+        Overflow::DropNewest => return Ok(()),
+        Overflow::Disconnect => {
+          queue.messages.clear();
+          queue.close();
+          return Err(());
+        },
+      }
+    }
+    queue.messages.push_back(event);
+    if let Some(task) = queue.reader.take() {
+      task.notify();
+    }
+    Ok(())
+  }
+
+  /**
+  Runs `f` once the client disconnects, meaning its event stream or WebSocket was dropped. If the
+  client is already gone, `f` runs right away. `f` runs on whichever thread drops the stream, so it
+  shouldn't block.
+  */
+  pub fn on_disconnect<F: FnOnce() + Send + 'static>(&mut self, f: F) {
+    let mut queue = self.queue.lock().unwrap();
+    if queue.closed {
+      drop(queue);
+      f();
+    } else {
+      queue.on_disconnect = Some(Box::new(f));
+    }
+  }
+
+  /// Returns true once the client has disconnected, so sending to it will always fail.
+  pub fn is_closed(&self) -> bool {
+    self.queue.lock().unwrap().closed
+  }
+}
+
+impl Drop for Sender {
+  fn drop(&mut self) {
+    self.queue.lock().unwrap().close();
+  }
+}
+
+impl Stream for Receiver {
+  type Item = Event;
+  type Error = ();
+
+  fn poll(&mut self) -> Poll<Option<Event>, ()> {
+    let mut queue = self.queue.lock().unwrap();
+    match queue.messages.pop_front() {
+      Some(msg) => Ok(Async::Ready(Some(msg))),
+      None if queue.closed => Ok(Async::Ready(None)),
+      None => {
+        queue.reader = Some(task::current());
+        Ok(Async::NotReady)
+      },
+    }
+  }
+}
+
+impl Drop for Receiver {
+  fn drop(&mut self) {
+    let on_disconnect = {
+      let mut queue = self.queue.lock().unwrap();
+      queue.messages.clear();
+      queue.closed = true;
+      queue.on_disconnect.take()
+    };
+    // called without the lock, since it may well send to this sender
+    if let Some(f) = on_disconnect {
+      f();
+    }
   }
 }
 
@@ -55,6 +247,43 @@ pub trait Channel: Send + Sync {
   */
   fn join(&self, Sender, Option<String>, JsonObject);
 
+  /**
+  Called by `handle` to decide whether a client may listen, with the ID in the URL and the params
+  of the request. Return an error like `Error::unauthorized` or `Error::forbidden` to refuse the
+  client, which gets the error instead of an event stream. Defaults to letting everyone listen.
+
+  With the default `handle`, the client joins the channel while this runs, so it doesn't miss events
+  sent in the meantime, but it only gets them once this succeeds. A refused client is disconnected,
+  just like a client that went away. Channels that tell other clients who joined, like
+  `MemoryChannel` with presence, should override `handle` so clients only join once they're
+  authorized.
+  */
+  fn authorize(&self, id: &Option<String>, params: &JsonObject) -> BoxFuture<(), Error> {
+    let _ = (id, params);
+    ok(()).boxed()
+  }
+
+  /**
+  Called instead of `join` when an event-stream client reconnects, with the id of the last event
+  it got, from the `Last-Event-ID` header. Channels that send events with `Sender::send_with_id`
+  can send the client the events it missed, before adding it like `join` does. The default
+  implementation just calls `join`.
+  */
+  fn rejoin(&self, sender: Sender, id: Option<String>, params: JsonObject, last_event_id: &str) {
+    let _ = last_event_id;
+    self.join(sender, id, params)
+  }
+
+  /**
+  Lists the clients currently listening to the ID, or to the whole collection for `None`, with the
+  metadata the channel keeps about each of them, like a user's name from their params. Channels
+  that don't track presence return an empty list, which is the default.
+  */
+  fn presence(&self, id: &Option<String>) -> Vec<JsonObject> {
+    let _ = id;
+    Vec::new()
+  }
+
   /**
   Called by application code to send a new message to connected clients. Channel implementors are
   also free to add additional functions that send messages with additional paramaters, such as
@@ -63,6 +292,31 @@ pub trait Channel: Send + Sync {
   */
   fn send(&self, &str, &JsonObject);
 
+  /**
+  Sends a message about the object with the id `id`, like `"created"` with the new object. It
+  should go to clients listening to that id, and to clients listening to the whole collection.
+
+  The default implementation calls `send`, which is fine for channels that don't keep track of
+  which ids their clients are listening to.
+  */
+  fn send_about(&self, id: &str, event_type: &str, msg: &JsonObject) {
+    let _ = id;
+    self.send(event_type, msg)
+  }
+
+  /**
+  The maximum number of messages queued for each client, and what to do with new messages once a
+  client's queue is full. Defaults to 1000 messages, dropping the oldest ones.
+  */
+  fn queue_limit(&self) -> (usize, Overflow) {
+    (DEFAULT_QUEUE_LIMIT, Overflow::DropOldest)
+  }
+
+  /// Heartbeat and retry settings for this channel's event streams. Defaults to the `Server`'s.
+  fn stream_options(&self) -> StreamOptions {
+    StreamOptions::default()
+  }
+
   /**
   Takes a `Request` and returns a `Reply` future with a streaming `Reply` body. If you're using a
   channel in your server's application code, this is the function you'll want to use.
@@ -71,10 +325,118 @@ pub trait Channel: Send + Sync {
     if req.method().clone() != Method::Listen {
       return Error::server_error("passed a non-listen request to channel")
     }
+    let authorized = self.authorize(req.id(), req.params());
     let params = req.params().clone();
     let id = req.id().clone();
-    let (sender, reply) = make_streamed_reply(req);
-    self.join(sender, id, params);
-    ok(reply).boxed()
+    let last_event_id = req.last_event_id().map(|s| s.to_string());
+    let (limit, overflow) = self.queue_limit();
+    let (sender, reply) = make_streamed_reply(req, limit, overflow, self.stream_options());
+    match last_event_id {
+      Some(last) => self.rejoin(sender, id, params, &last),
+      None => self.join(sender, id, params),
+    }
+    // dropping the reply if this fails disconnects the client
+    authorized.map(move |_| reply).boxed()
+  }
+}
+
+impl<C: Channel + ?Sized> Channel for Arc<C> {
+  fn join(&self, sender: Sender, id: Option<String>, params: JsonObject) {
+    (**self).join(sender, id, params)
+  }
+
+  fn authorize(&self, id: &Option<String>, params: &JsonObject) -> BoxFuture<(), Error> {
+    (**self).authorize(id, params)
+  }
+
+  fn rejoin(&self, sender: Sender, id: Option<String>, params: JsonObject, last_event_id: &str) {
+    (**self).rejoin(sender, id, params, last_event_id)
+  }
+
+  fn presence(&self, id: &Option<String>) -> Vec<JsonObject> {
+    (**self).presence(id)
+  }
+
+  fn send(&self, event_type: &str, msg: &JsonObject) {
+    (**self).send(event_type, msg)
+  }
+
+  fn send_about(&self, id: &str, event_type: &str, msg: &JsonObject) {
+    (**self).send_about(id, event_type, msg)
+  }
+
+  fn queue_limit(&self) -> (usize, Overflow) {
+    (**self).queue_limit()
+  }
+
+  fn stream_options(&self) -> StreamOptions {
+    (**self).stream_options()
+  }
+
+  fn handle(&self, req: Request) -> BoxFuture<Reply, Error> {
+    (**self).handle(req)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use {JsonValue};
+
+  fn msg(n: u64) -> JsonObject {
+    let mut obj = JsonObject::new();
+    obj.insert("n".to_string(), JsonValue::from(n));
+    obj
+  }
+
+  fn received(sender: Sender, receiver: Receiver) -> Vec<u64> {
+    drop(sender);
+    receiver.map(|(_, _, obj)| obj.get("n").unwrap().as_u64().unwrap()).collect().wait().unwrap()
+  }
+
+  #[test]
+  fn applies_overflow_policies() {
+    let (mut sender, receiver) = new_queue(2, Overflow::DropOldest);
+    for n in 0..4 {
+      assert!(sender.send("n", msg(n)).is_ok());
+    }
+    assert_eq!(received(sender, receiver), vec![2, 3]);
+
+    let (mut sender, receiver) = new_queue(2, Overflow::DropNewest);
+    for n in 0..4 {
+      assert!(sender.send("n", msg(n)).is_ok());
+    }
+    assert_eq!(received(sender, receiver), vec![0, 1]);
+
+    let (mut sender, receiver) = new_queue(2, Overflow::Disconnect);
+    assert!(sender.send("n", msg(0)).is_ok());
+    assert!(sender.send("n", msg(1)).is_ok());
+    assert!(sender.send("n", msg(2)).is_err());
+    assert!(sender.is_closed());
+    assert_eq!(received(sender, receiver), Vec::<u64>::new());
+  }
+
+  #[test]
+  fn filters_and_maps_events() {
+    let (mut sender, receiver) = new_queue(10, Overflow::DropOldest);
+    sender.filter_map(|event, val| if val.get("n").unwrap() == 1 { None } else { Some((event, val)) });
+    sender.filter_map(|event, mut val| {
+      let n = val.get("n").unwrap().as_u64().unwrap();
+      val.insert("n".to_string(), JsonValue::from(n * 10));
+      Some((event, val))
+    });
+    for n in 0..3 {
+      assert!(sender.send("n", msg(n)).is_ok());
+    }
+    assert_eq!(received(sender, receiver), vec![0, 20]);
+  }
+
+  #[test]
+  fn closes_when_the_client_disconnects() {
+    let (mut sender, receiver) = new_queue(2, Overflow::DropOldest);
+    assert!(!sender.is_closed());
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert!(sender.send("n", msg(0)).is_err());
   }
 }
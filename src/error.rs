@@ -16,8 +16,8 @@ wrapping in a future or boxing.
 
 If you need custom JSON in your error, you can use the `Error::new` function directly.
 
-Currently there's no way to access or change the insides of an `Error`, but that probably will
-change in the near future.
+You can read the insides of an `Error` with the `kind`, `data` and `status_code` functions, but
+currently there's no way to change them, although that probably will change in the near future.
 */
 #[derive(Debug)]
 pub struct Error {
@@ -46,6 +46,8 @@ pub enum ErrorKind {
   Unavailable,
   /// This HTTP method isn't allowed at this URL, and another method would be valid.
   MethodNotAllowed,
+  /// The request conflicts with the current state of the data, like creating an object with an ID that's taken.
+  Conflict,
 }
 
 impl ErrorKind {
@@ -59,6 +61,7 @@ impl ErrorKind {
       &ErrorKind::ServerError => StatusCode::InternalServerError,
       &ErrorKind::Unavailable => StatusCode::ServiceUnavailable,
       &ErrorKind::MethodNotAllowed => StatusCode::MethodNotAllowed,
+      &ErrorKind::Conflict => StatusCode::Conflict,
     }
   }
 
@@ -75,6 +78,7 @@ impl ErrorKind {
       &ErrorKind::ServerError => "server",
       &ErrorKind::Unavailable => "server",
       &ErrorKind::MethodNotAllowed => "bad_request",
+      &ErrorKind::Conflict => "conflict",
     }.to_string()
   }
 }
@@ -127,14 +131,37 @@ impl Error {
   pub fn method_not_allowed<T: Send + 'static>(msg: &str) -> BoxFuture<T, Error> {
     err(std_error(ErrorKind::MethodNotAllowed, msg)).boxed()
   }
+  pub fn conflict<T: Send + 'static>(msg: &str) -> BoxFuture<T, Error> {
+    err(std_error(ErrorKind::Conflict, msg)).boxed()
+  }
+
+  /// The type of this error, like `ErrorKind::NotFound`.
+  pub fn kind(&self) -> &ErrorKind {
+    &self.kind
+  }
+
+  /// The JSON data that will be sent to the client.
+  pub fn data(&self) -> &JsonValue {
+    &self.data
+  }
+
+  /// The HTTP status code for this error, for instance `404` for `ErrorKind::NotFound`.
+  pub fn status_code(&self) -> u16 {
+    self.kind.to_hyper_status().into()
+  }
 
   pub fn to_http(self) -> http::Response<Body> {
-    let resp = http::Response::new();
-    let resp_str = self.data.to_string();
-    resp
-      .with_status(self.kind.to_hyper_status())
-      .with_header(ContentLength(resp_str.len() as u64))
-      .with_header(ContentType(mime::APPLICATION_JSON))
-      .with_body(Body::Once(Some(resp_str.into())))
+    let (resp, body) = to_http_parts(self);
+    resp.with_body(body)
   }
 }
+
+// only used internally, returns the response without a body, and the body separately
+pub fn to_http_parts<B>(error: Error) -> (http::Response<B>, Body) {
+  let resp_str = error.data.to_string();
+  let resp = http::Response::new()
+    .with_status(error.kind.to_hyper_status())
+    .with_header(ContentLength(resp_str.len() as u64))
+    .with_header(ContentType(mime::APPLICATION_JSON));
+  (resp, Body::Once(Some(resp_str.into())))
+}
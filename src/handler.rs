@@ -9,6 +9,29 @@ any closure with the signature `Fn(Request) -> Future<Reply, Error>` is automati
 */
 pub trait Handler: Send + Sync {
   fn handle(&self, req: Request) -> BoxFuture<Reply, Error>;
+
+  /**
+  The actions this handler supports. Requests for any other action get a `MethodNotAllowed` error
+  without reaching the handler.
+
+  Defaults to `None`, which passes every `POST` action on an item through to the handler. `GET`
+  actions and actions on the whole collection are only routed if they're declared here, so a link
+  or a crawler can't trigger an action, and `GET /cats/stats` stays a `Get` request for the cat
+  with ID `stats` unless `stats` is declared.
+  */
+  fn actions(&self) -> Option<Vec<Action>> {
+    None
+  }
+
+  /**
+  Whether this handler takes `PATCH` requests with `Content-Type: application/json-patch+json`,
+  whose operations are in `Request::json_patch` instead of the request data. Defaults to `false`,
+  which refuses them with a `BadRequest` error without reaching the handler, so code that only
+  checks the data of a `Patch` request can't be bypassed.
+  */
+  fn accepts_json_patch(&self) -> bool {
+    false
+  }
 }
 
 impl <T, F> Handler for T
@@ -18,3 +41,51 @@ impl <T, F> Handler for T
     self(req).boxed()
   }
 }
+
+/**
+Declares an action a `Handler` supports, which is requested as a `Method::Action`.
+
+By default, an action is on a single item and reached with `POST`, like `POST /cats/123/feed`.
+Actions on the whole collection, like `POST /cats/bulk-import`, have no ID. Read-only actions that
+compute a view of the data, like `GET /cats/123/stats`, are reached with `GET` instead.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Action {
+  name: String,
+  collection: bool,
+  get: bool,
+}
+
+impl Action {
+  pub fn new<S: Into<String>>(name: S) -> Action {
+    Action {
+      name: name.into(),
+      collection: false,
+      get: false,
+    }
+  }
+
+  /// Makes this an action on the whole collection, rather than on a single item.
+  pub fn collection(mut self) -> Action {
+    self.collection = true;
+    self
+  }
+
+  /// Makes this a read-only action reached with `GET`, rather than `POST`.
+  pub fn get(mut self) -> Action {
+    self.get = true;
+    self
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn is_collection(&self) -> bool {
+    self.collection
+  }
+
+  pub fn is_get(&self) -> bool {
+    self.get
+  }
+}
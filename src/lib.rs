@@ -9,37 +9,70 @@ feedback, comments, suggestions, pull requests, bug reports, they'd be much appr
 don't really know what I'm doing tbqh.
 */
 
+#[macro_use]
 extern crate futures;
 extern crate tokio_core;
 #[macro_use]
+extern crate serde;
+#[macro_use]
 extern crate serde_json;
 extern crate hyper;
 extern crate queryst_prime as queryst;
 extern crate uuid;
+extern crate tokio_io;
+extern crate tokio_codec;
+extern crate bytes;
+extern crate sha1;
+extern crate base64;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+#[cfg(feature = "sqlite")]
+extern crate futures_cpupool;
 
 pub use serde_json::Value as JsonValue;
 pub type JsonObject = serde_json::value::Map<String, JsonValue>;
 
+// builds a `JsonObject` out of a `json!({...})` value in tests
+#[cfg(test)]
+fn obj(val: JsonValue) -> JsonObject {
+  match val {
+    JsonValue::Object(o) => o,
+    _ => panic!("not an object"),
+  }
+}
+
 mod request;
 pub use request::{Request, Method};
 
 mod server;
-pub use server::Server;
+pub use server::{Server, ListenError};
 
 mod reply;
 pub use reply::Reply;
 
 mod adapter;
-pub use adapter::Adapter;
+pub use adapter::{Adapter, Published};
 
 mod handler;
-pub use handler::{Handler};
+pub use handler::{Handler, Action};
+
+mod resource;
+pub use resource::Resource;
 
 mod channel;
-pub use channel::{Channel, Sender};
+pub use channel::{Channel, Sender, Overflow, StreamOptions};
 
 mod error;
 pub use error::{Error, ErrorKind};
 
+mod websocket;
+mod poll;
+mod typed;
+
 pub mod memory;
+pub mod broker;
 pub mod util;
+pub mod query;
+pub mod patch;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
@@ -1,100 +1,169 @@
-use futures::future::{Future, BoxFuture, ok, err};
+use futures::future::{Future, BoxFuture, ok, err, result};
 use {JsonValue, ErrorKind, Adapter, JsonObject};
-use std::collections::HashMap;
+use super::store::{Store, Change, IdStrategy};
 use std::sync::Mutex;
 
-fn std_error(kind: ErrorKind, err_str: &str) -> (ErrorKind, JsonValue) {
-  let val = json!({
-    "error": {
-      "type": kind.as_string(),
-      "message": err_str.to_string(),
-    }
-  });
-  (kind, val)
-}
-
 pub struct MemoryAdapter {
-  inside: Mutex<Inside>,
-}
-
-struct Inside {
-  datastore: HashMap<String, JsonObject>,
-  last_num: i64,
+  inside: Mutex<Store>,
 }
 
 impl MemoryAdapter {
   pub fn new() -> MemoryAdapter {
     MemoryAdapter {
-      inside: Mutex::new(Inside {
-        datastore: HashMap::new(),
-        last_num: 0,
-      }),
+      inside: Mutex::new(Store::new()),
     }
   }
+
+  /// Sets how posted objects get their ids. Defaults to `IdStrategy::Sequential`.
+  pub fn with_ids(mut self, ids: IdStrategy) -> MemoryAdapter {
+    self.inside.get_mut().unwrap().ids = ids;
+    self
+  }
+
+  /// Sets the field objects keep their id in, like `"_id"`. Defaults to `"id"`.
+  pub fn with_id_field(mut self, field: &str) -> MemoryAdapter {
+    self.inside.get_mut().unwrap().id_field = field.to_string();
+    self
+  }
+}
+
+fn commit(store: &mut Store, res: Result<(Change, JsonObject), (ErrorKind, JsonValue)>) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+  match res {
+    Ok((change, reply)) => {
+      store.apply(change);
+      ok(reply).boxed()
+    },
+    Err(e) => err(e).boxed(),
+  }
 }
 
 impl Adapter for MemoryAdapter {
-  /// currently this function only supports equality matching — we'd probably want to add more
-  /// kinds of matching and querying in the future, maybe by building into query object
+  /// filters with the query language in the `query` module, so `?age[$gt]=3` and friends work,
+  /// and sorts and paginates with `$sort`, `$limit`, `$skip` and `$cursor`
   fn list(&self, params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
-    let inside = self.inside.lock().unwrap();
-    let res: Vec<JsonValue> = inside.datastore
-      .iter()
-      .map(|(_, item)| item)
-      .filter(|item| {
-        for (param_key, param_val) in params {
-          if item.get(param_key) != Some(param_val) {
-            return false;
-          }
-        }
-        true
-      })
-      .map(|item| JsonValue::Object(item.clone()))
-      .collect();
-    let mut dat = JsonObject::new();
-    dat.insert("data".to_string(), JsonValue::Array(res));
-    ok(dat).boxed()
+    result(self.inside.lock().unwrap().list(params)).boxed()
   }
 
   fn get(&self, id: &str, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
-    let inside = self.inside.lock().unwrap();
-    match inside.datastore.get(id) {
-      Some(val) => ok(val.clone()).boxed(),
-      None => err(std_error(ErrorKind::NotFound, "couldn't find object with that id")).boxed(),
-    }
+    result(self.inside.lock().unwrap().get(id)).boxed()
   }
 
   fn post(&self, data: &JsonObject, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
     let mut inside = self.inside.lock().unwrap();
-    inside.last_num += 1;
-    let mut data = data.clone(); // TODO remove clones?
-    let id_str = inside.last_num.to_string();
-    data.insert("id".to_string(), JsonValue::String(id_str.clone()));
-    inside.datastore.insert(id_str, data.clone());
-    ok(data).boxed()
+    let res = inside.post(data);
+    commit(&mut inside, res)
   }
 
   fn patch(&self, id: &str, data: &JsonObject, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
     let mut inside = self.inside.lock().unwrap();
-    if let Some(_) = data.get("id") {
-      return err(std_error(ErrorKind::BadRequest, "can't update id")).boxed();
-    }
-    let dbdata = match inside.datastore.get_mut(id) {
-      Some(val) => val,
-      None => return err(std_error(ErrorKind::NotFound, "couldn't find object with that id")).boxed(),
-    };
-    // TODO should probably recursively update children too instead of replacing, there's a JSON update spec that you can read
-    for (key, val) in data.clone().into_iter() {
-      dbdata.insert(key, val);
-    }
-    ok(dbdata.clone()).boxed()
+    let res = inside.patch(id, data);
+    commit(&mut inside, res)
+  }
+
+  /// applies the operations to a copy of the object, so it's untouched if any of them fail
+  fn json_patch(&self, id: &str, ops: &[JsonValue], _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let mut inside = self.inside.lock().unwrap();
+    let res = inside.json_patch(id, ops);
+    commit(&mut inside, res)
+  }
+
+  fn update(&self, id: &str, data: &JsonObject, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let mut inside = self.inside.lock().unwrap();
+    let res = inside.update(id, data);
+    commit(&mut inside, res)
   }
 
   fn delete(&self, id: &str, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
     let mut inside = self.inside.lock().unwrap();
-    inside.datastore.remove(id);
+    let res = inside.delete(id);
+    commit(&mut inside, res)
+  }
+
+  fn id_field(&self) -> String {
+    self.inside.lock().unwrap().id_field.clone()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use {Request, Method};
+
+  fn put(adapter: &MemoryAdapter, id: &str, data: JsonValue) -> Result<JsonObject, u16> {
+    let data = match data {
+      JsonValue::Object(o) => o,
+      _ => panic!("not an object"),
+    };
+    let req = Request::new("/cats".to_string(), Method::Put, Some(id.to_string()), data, JsonObject::new());
+    adapter.handle(req).wait().map(|reply| reply.data().unwrap().clone()).map_err(|e| e.status_code())
+  }
+
+  #[test]
+  fn replaces_objects() {
+    let adapter = MemoryAdapter::new();
+    let tom = put(&adapter, "5", json!({"name": "Tom", "age": 3})).unwrap();
+    assert_eq!(tom.get("id").unwrap(), "5");
+    let tom = put(&adapter, "5", json!({"name": "Tom", "id": "5"})).unwrap();
+    assert!(tom.get("age").is_none());
+    assert_eq!(adapter.get("5", &JsonObject::new()).wait().unwrap(), tom);
+    assert_eq!(put(&adapter, "5", json!({"id": "6"})), Err(400));
+    // posts skip the ids picked by clients, however large they are
+    put(&adapter, "1", json!({})).unwrap();
+    put(&adapter, "9223372036854775807", json!({})).unwrap();
+    let sam = adapter.post(&JsonObject::new(), &JsonObject::new()).wait().unwrap();
+    assert_eq!(sam.get("id").unwrap(), "2");
+    assert!(adapter.post(&JsonObject::new(), &JsonObject::new()).wait().is_ok());
+    // running out of ids is an error, not a panic that poisons the lock
+    adapter.inside.lock().unwrap().last_num = i64::max_value();
+    assert!(adapter.post(&JsonObject::new(), &JsonObject::new()).wait().is_err());
+    assert!(adapter.get("2", &JsonObject::new()).wait().is_ok());
+  }
+
+  #[test]
+  fn applies_patches() {
+    let adapter = MemoryAdapter::new();
+    put(&adapter, "1", json!({"name": "Tom", "owner": {"name": "Jon", "phone": "555"}})).unwrap();
+    let mut req = Request::new("/cats".to_string(), Method::Patch, Some("1".to_string()), JsonObject::new(), JsonObject::new());
+    req.set_json_patch(Some(vec![
+      json!({"op": "replace", "path": "/name", "value": "Sam"}),
+      json!({"op": "test", "path": "/name", "value": "Tom"}),
+    ]));
+    assert_eq!(adapter.handle(req).wait().err().unwrap().status_code(), 400);
+    assert_eq!(adapter.get("1", &JsonObject::new()).wait().unwrap().get("name").unwrap(), "Tom");
+
+    let mut req = Request::new("/cats".to_string(), Method::Patch, Some("1".to_string()), JsonObject::new(), JsonObject::new());
+    req.set_json_patch(Some(vec![json!({"op": "remove", "path": "/owner/phone"})]));
+    adapter.handle(req).wait().unwrap();
+    let patch = match json!({"owner": {"city": "Paris"}, "name": null}) {
+      JsonValue::Object(o) => o,
+      _ => unreachable!(),
+    };
+    let tom = adapter.patch("1", &patch, &JsonObject::new()).wait().unwrap();
+    assert_eq!(JsonValue::Object(tom), json!({"id": "1", "owner": {"name": "Jon", "city": "Paris"}}));
+  }
+
+  #[test]
+  fn picks_ids() {
+    let adapter = MemoryAdapter::new().with_ids(IdStrategy::Uuid).with_id_field("_id");
+    let tom = adapter.post(&JsonObject::new(), &JsonObject::new()).wait().unwrap();
+    let id = tom.get("_id").unwrap().as_str().unwrap().to_string();
+    assert_eq!(id.len(), 36);
+    assert!(tom.get("id").is_none());
+    assert_eq!(adapter.get(&id, &JsonObject::new()).wait().unwrap(), tom);
+    let list = adapter.list(&JsonObject::new()).wait().unwrap();
+    assert_eq!(list.get("data").unwrap().as_array().unwrap().len(), 1);
+
+    let adapter = MemoryAdapter::new().with_ids(IdStrategy::Client);
     let mut data = JsonObject::new();
-    data.insert("id".to_string(), JsonValue::String(id.to_string()));
-    ok(data).boxed()
+    assert_eq!(adapter.post(&data, &JsonObject::new()).wait().err().unwrap().0.as_string(), "bad_request");
+    data.insert("id".to_string(), json!("tom"));
+    assert_eq!(adapter.post(&data, &JsonObject::new()).wait().unwrap().get("id").unwrap(), "tom");
+    assert_eq!(adapter.post(&data, &JsonObject::new()).wait().err().unwrap().0.as_string(), "conflict");
+
+    let adapter = MemoryAdapter::new().with_ids(IdStrategy::Custom(Box::new(|data| {
+      data.get("name").and_then(|n| n.as_str()).unwrap_or("nobody").to_lowercase()
+    })));
+    data.insert("name".to_string(), json!("Sam"));
+    assert_eq!(adapter.post(&data, &JsonObject::new()).wait().unwrap().get("id").unwrap(), "sam");
   }
 }
@@ -1,27 +1,581 @@
-use {Sender, Channel, JsonObject};
-use std::sync::Mutex;
+use {Sender, Channel, JsonObject, Error, Overflow, StreamOptions, Request, Reply, Method};
+use reply::make_streamed_reply;
+use channel::DEFAULT_QUEUE_LIMIT;
+use query::Query;
+use futures::{Future, IntoFuture};
+use futures::future::{ok, err, BoxFuture};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
 
+// a connected client, the query its messages have to match, and its presence metadata if the
+// channel tracks presence
+struct Listener {
+  sender: Sender,
+  filter: Query,
+  presence: Option<JsonObject>,
+}
+
+impl Listener {
+  fn send(&mut self, event: &Sent) {
+    if self.filter.matches(&event.msg) {
+      let _ = self.sender.send_with_id(event.id, event.kind.clone(), event.msg.clone());
+    }
+  }
+}
+
+// a message that was sent, kept around for clients that reconnect
+struct Sent {
+  id: u64,
+  // the id the message is about, or `None` if it was sent to everyone
+  about: Option<String>,
+  kind: String,
+  msg: JsonObject,
+}
+
+// sends the message to every listener, dropping the ones that have disconnected
+fn send_to(listeners: &mut Vec<Listener>, event: &Sent) {
+  for listener in listeners.iter_mut() {
+    listener.send(event);
+  }
+  drop_closed(listeners);
+}
+
+// drops the listeners that have disconnected, and sends a `leave` event about them to the rest
+fn drop_closed(listeners: &mut Vec<Listener>) {
+  let (open, closed): (Vec<Listener>, Vec<Listener>) = listeners.drain(..).partition(|l| !l.sender.is_closed());
+  *listeners = open;
+  for presence in closed.into_iter().filter_map(|l| l.presence) {
+    for listener in listeners.iter_mut() {
+      let _ = listener.sender.send("leave", presence.clone());
+    }
+  }
+}
+
+struct Rooms {
+  // listeners on the whole collection, like `/cats`
+  collection: Vec<Listener>,
+  // listeners on a single id, like `/cats/123`
+  ids: HashMap<String, Vec<Listener>>,
+  last_event_id: u64,
+  // the most recent messages, oldest first
+  history: VecDeque<Sent>,
+}
+
+impl Rooms {
+  // gives the message the next event id, and remembers it for reconnecting clients
+  fn record(&mut self, about: Option<&str>, message_kind: &str, msg: &JsonObject, replay_limit: usize) {
+    self.last_event_id += 1;
+    if self.history.len() >= replay_limit.max(1) {
+      self.history.pop_front();
+    }
+    self.history.push_back(Sent {
+      id: self.last_event_id,
+      about: about.map(|s| s.to_string()),
+      kind: message_kind.to_string(),
+      msg: msg.clone(),
+    });
+  }
+
+  // drops the listeners that have disconnected, and the rooms nobody's listening to anymore
+  fn prune(&mut self) {
+    drop_closed(&mut self.collection);
+    for room in self.ids.values_mut() {
+      drop_closed(room);
+    }
+    self.ids.retain(|_, room| !room.is_empty());
+  }
+
+  // puts a listener in its room, first sending it the messages after `last_event_id`, and telling
+  // the others in the room it joined
+  fn add(&mut self, mut listener: Listener, id: Option<String>, last_event_id: Option<u64>) {
+    self.prune();
+    if let Some(last) = last_event_id {
+      let missed = self.history.iter().filter(|sent| {
+        sent.id > last && (id.is_none() || sent.about.is_none() || sent.about == id)
+      });
+      for sent in missed {
+        listener.send(sent);
+      }
+    }
+    let room = self.room_mut(&id);
+    if let Some(ref presence) = listener.presence {
+      for other in room.iter_mut() {
+        let _ = other.sender.send("join", presence.clone());
+      }
+    }
+    room.push(listener);
+  }
+
+  fn room(&self, id: &Option<String>) -> Option<&Vec<Listener>> {
+    match id {
+      &Some(ref id) => self.ids.get(id),
+      &None => Some(&self.collection),
+    }
+  }
+
+  fn room_mut(&mut self, id: &Option<String>) -> &mut Vec<Listener> {
+    match id {
+      &Some(ref id) => self.ids.entry(id.clone()).or_insert_with(Vec::new),
+      &None => &mut self.collection,
+    }
+  }
+}
+
+/**
+A `Channel` that keeps its clients in memory, in rooms for each id they're listening to.
+
+Messages sent with `send_about` go to the clients listening to that id, and to clients listening to
+the whole collection, while messages sent with `send` go to everyone.
+
+Channels created with `MemoryChannel::filtered` also treat some params of a `Listen` request as a
+query, like the one `MemoryAdapter::list` takes, so a client listening to `/cats?color=grey` only
+gets messages about grey cats. Only the params named when creating the channel are part of the
+query, so params like tokens aren't, and neither are the path params named in `with_path_params`,
+however the client joined. Clients with an invalid query get a `BadRequest` error.
+
+To refuse some clients, for instance ones without a valid token in their params, add a check with
+`MemoryChannel::with_authorization`.
+
+Clients that have disconnected are dropped the next time a message is sent to them, or when another
+client joins.
+
+Every message gets an increasing event id, and the channel remembers the last 100 messages, so
+event-stream clients that reconnect with a `Last-Event-ID` get sent the messages they missed.
+
+Channels created with `with_presence` keep track of who's listening to each id, which `presence`
+lists. Clients listening to the same id, or to the whole collection, are sent a `join` or `leave`
+event with the presence metadata when another client joins or disconnects.
+*/
 pub struct MemoryChannel {
-  senders: Mutex<Vec<Sender>>,
+  // shared with the senders, so they can tell the channel when their client disconnects
+  rooms: Arc<Mutex<Rooms>>,
+  // the params that filter messages, and the path params that never do
+  filters: Vec<String>,
+  path_params: Vec<String>,
+  queue_limit: (usize, Overflow),
+  replay_limit: usize,
+  stream_options: StreamOptions,
+  authorization: Option<Box<Fn(&Option<String>, &JsonObject) -> BoxFuture<(), Error> + Send + Sync>>,
+  event_filter: Option<Arc<Fn(&JsonObject, String, JsonObject) -> Option<(String, JsonObject)> + Send + Sync>>,
+  presence: Option<Box<Fn(&JsonObject) -> JsonObject + Send + Sync>>,
 }
 
 impl MemoryChannel {
   pub fn new() -> MemoryChannel {
     MemoryChannel {
-      senders: Mutex::new(Vec::new()),
+      rooms: Arc::new(Mutex::new(Rooms {
+        collection: Vec::new(),
+        ids: HashMap::new(),
+        last_event_id: 0,
+        history: VecDeque::new(),
+      })),
+      filters: Vec::new(),
+      path_params: Vec::new(),
+      queue_limit: (DEFAULT_QUEUE_LIMIT, Overflow::DropOldest),
+      replay_limit: 100,
+      stream_options: StreamOptions::default(),
+      authorization: None,
+      event_filter: None,
+      presence: None,
+    }
+  }
+
+  /**
+  Creates a channel that only sends clients the messages matching their params named in `filters`,
+  like `&["color", "age"]`. Other params are left out of the query.
+  */
+  pub fn filtered(filters: &[&str]) -> MemoryChannel {
+    MemoryChannel {
+      filters: filters.iter().map(|f| f.to_string()).collect(),
+      ..MemoryChannel::new()
+    }
+  }
+
+  /**
+  Sets the path params of the routes this channel listens on, like `user_id` for
+  `/users/:user_id/cats`, which aren't used to filter messages even if `filtered` names them.
+  */
+  pub fn with_path_params(mut self, names: &[&str]) -> MemoryChannel {
+    self.path_params = names.iter().map(|n| n.to_string()).collect();
+    self
+  }
+
+  /**
+  Sets the maximum number of messages queued for each client, and what happens to new messages
+  when a client's queue is full. Panics if `limit` is 0.
+  */
+  pub fn with_queue_limit(mut self, limit: usize, overflow: Overflow) -> MemoryChannel {
+    assert!(limit > 0, "a client's queue needs room for at least one message");
+    self.queue_limit = (limit, overflow);
+    self
+  }
+
+  /// Sets how many of the most recent messages are kept for clients that reconnect. Defaults to 100.
+  pub fn with_replay_limit(mut self, limit: usize) -> MemoryChannel {
+    self.replay_limit = limit;
+    self
+  }
+
+  /// Sets the heartbeat and retry settings for this channel's event streams.
+  pub fn with_stream_options(mut self, options: StreamOptions) -> MemoryChannel {
+    self.stream_options = options;
+    self
+  }
+
+  /**
+  Sets a check that runs before clients join, with the ID they're listening to and their params.
+  Clients get the check's error, like `Error::unauthorized`, instead of an event stream if it fails.
+  See `Channel::authorize`.
+  */
+  pub fn with_authorization<F, R>(mut self, check: F) -> MemoryChannel
+    where F: Fn(&Option<String>, &JsonObject) -> R + Send + Sync + 'static,
+          R: IntoFuture<Item=(), Error=Error>,
+          R::Future: Send + 'static
+  {
+    self.authorization = Some(Box::new(move |id, params| check(id, params).into_future().boxed()));
+    self
+  }
+
+  /**
+  Sets a function that runs on every event before it's sent to a client, with the client's params
+  and the event's type and data. It can change the event, for instance to strip fields the client
+  isn't allowed to see, or return `None` to not send it to that client. See `Sender::filter_map`.
+  */
+  pub fn with_event_filter<F>(mut self, f: F) -> MemoryChannel
+    where F: Fn(&JsonObject, String, JsonObject) -> Option<(String, JsonObject)> + Send + Sync + 'static
+  {
+    self.event_filter = Some(Arc::new(f));
+    self
+  }
+
+  /**
+  Tracks the presence of clients, with metadata made from their params by `f`. For instance, `f`
+  could pick out the user's name from the params, leaving out things like tokens.
+  */
+  pub fn with_presence<F>(mut self, f: F) -> MemoryChannel
+    where F: Fn(&JsonObject) -> JsonObject + Send + Sync + 'static
+  {
+    self.presence = Some(Box::new(f));
+    self
+  }
+
+  // adds the client, after sending it the messages it missed since `last_event_id`
+  // an invalid query drops the sender, which ends the stream; `authorize` checks this first.
+  fn add(&self, sender: Sender, id: Option<String>, params: JsonObject, last_event_id: Option<u64>) {
+    if let Ok(filter) = self.filter_for(&params) {
+      let listener = self.listener(sender, &id, params, filter);
+      self.rooms.lock().unwrap().add(listener, id, last_event_id);
+    }
+  }
+
+  // wraps up a new client
+  fn listener(&self, mut sender: Sender, id: &Option<String>, params: JsonObject, filter: Query) -> Listener {
+    let presence = self.presence.as_ref().map(|f| f(&params));
+    if let Some(ref event_filter) = self.event_filter {
+      let event_filter = event_filter.clone();
+      sender.filter_map(move |event, val| event_filter(&params, event, val));
+    }
+    if presence.is_some() {
+      // drops the client from the room right away, so the others hear it left
+      let rooms = Arc::downgrade(&self.rooms);
+      let room_id = id.clone();
+      sender.on_disconnect(move || leave(rooms, room_id));
+    }
+    Listener {
+      sender: sender,
+      filter: filter,
+      presence: presence,
+    }
+  }
+
+  // the query made from the declared filter params, leaving out the path params
+  fn filter_for(&self, params: &JsonObject) -> Result<Query, Error> {
+    let query = params.iter()
+      .filter(|&(key, _)| self.filters.contains(key) && !self.path_params.contains(key))
+      .map(|(key, val)| (key.clone(), val.clone()))
+      .collect();
+    Query::parse(&query).map_err(|(kind, val)| Error::new(kind, val))
+  }
+
+  fn check_authorization(&self, id: &Option<String>, params: &JsonObject) -> BoxFuture<(), Error> {
+    match self.authorization {
+      Some(ref check) => check(id, params),
+      None => ok(()).boxed(),
     }
   }
 }
 
+fn leave(rooms: Weak<Mutex<Rooms>>, id: Option<String>) {
+  if let Some(rooms) = rooms.upgrade() {
+    let mut rooms = rooms.lock().unwrap();
+    drop_closed(rooms.room_mut(&id));
+    rooms.ids.retain(|_, room| !room.is_empty());
+  }
+}
+
 impl Channel for MemoryChannel {
-  fn join(&self, sender: Sender, _: Option<String>, _: JsonObject) {
-    self.senders.lock().unwrap().push(sender)
+  fn join(&self, sender: Sender, id: Option<String>, params: JsonObject) {
+    self.add(sender, id, params, None)
+  }
+
+  fn presence(&self, id: &Option<String>) -> Vec<JsonObject> {
+    let mut rooms = self.rooms.lock().unwrap();
+    rooms.prune();
+    rooms.room(id).map(|room| room.iter().filter_map(|l| l.presence.clone()).collect()).unwrap_or_default()
+  }
+
+  fn authorize(&self, id: &Option<String>, params: &JsonObject) -> BoxFuture<(), Error> {
+    match self.filter_for(params) {
+      Ok(_) => self.check_authorization(id, params),
+      Err(e) => err(e).boxed(),
+    }
+  }
+
+  fn rejoin(&self, sender: Sender, id: Option<String>, params: JsonObject, last_event_id: &str) {
+    self.add(sender, id, params, last_event_id.parse().ok())
+  }
+
+  /// only puts the client in its room once it's authorized, so other clients never hear about
+  /// clients that get refused. messages sent while the check runs are replayed to it from history.
+  fn handle(&self, req: Request) -> BoxFuture<Reply, Error> {
+    if req.method() != Method::Listen {
+      return Error::server_error("passed a non-listen request to channel")
+    }
+    let filter = match self.filter_for(req.params()) {
+      Ok(f) => f,
+      Err(e) => return err(e).boxed(),
+    };
+    let authorized = self.check_authorization(req.id(), req.params());
+    let id = req.id().clone();
+    let last_event_id = match req.last_event_id() {
+      Some(last) => last.parse().ok(),
+      None => Some(self.rooms.lock().unwrap().last_event_id),
+    };
+    let params = req.params().clone();
+    let (limit, overflow) = self.queue_limit;
+    let (sender, reply) = make_streamed_reply(req, limit, overflow, self.stream_options);
+    let listener = self.listener(sender, &id, params, filter);
+    let rooms = self.rooms.clone();
+    authorized.map(move |_| {
+      rooms.lock().unwrap().add(listener, id, last_event_id);
+      reply
+    }).boxed()
   }
 
   fn send(&self, message_kind: &str, msg: &JsonObject) {
-    for sender in self.senders.lock().unwrap().iter_mut() {
-      // TODO maybe handle this bug?
-      let _res = sender.send(message_kind, msg.clone());
+    let mut rooms = self.rooms.lock().unwrap();
+    let rooms = &mut *rooms;
+    rooms.record(None, message_kind, msg, self.replay_limit);
+    let sent = rooms.history.back().unwrap();
+    send_to(&mut rooms.collection, sent);
+    for room in rooms.ids.values_mut() {
+      send_to(room, sent);
+    }
+    rooms.ids.retain(|_, room| !room.is_empty());
+  }
+
+  fn send_about(&self, id: &str, message_kind: &str, msg: &JsonObject) {
+    let mut rooms = self.rooms.lock().unwrap();
+    let rooms = &mut *rooms;
+    rooms.record(Some(id), message_kind, msg, self.replay_limit);
+    let sent = rooms.history.back().unwrap();
+    send_to(&mut rooms.collection, sent);
+    let empty = match rooms.ids.get_mut(id) {
+      Some(room) => {
+        send_to(room, sent);
+        room.is_empty()
+      },
+      None => false,
+    };
+    if empty {
+      rooms.ids.remove(id);
     }
   }
+
+  fn queue_limit(&self) -> (usize, Overflow) {
+    self.queue_limit
+  }
+
+  fn stream_options(&self) -> StreamOptions {
+    self.stream_options
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use obj;
+  use {JsonValue, Request, Method};
+  use reply::take_event_stream;
+  use futures::Stream;
+  use futures::future::empty;
+
+  fn listen(chan: &MemoryChannel, id: Option<&str>, params: JsonValue) -> Result<::reply::EventReceiver, Error> {
+    let req = Request::new("/cats".to_string(), Method::Listen, id.map(|s| s.to_string()), JsonObject::new(), obj(params));
+    chan.handle(req).wait().map(|reply| take_event_stream(reply).ok().expect("not a streaming reply"))
+  }
+
+  // collects the events each stream got, once the channel is dropped and the streams end
+  fn received(chan: MemoryChannel, streams: Vec<::reply::EventReceiver>) -> Vec<Vec<String>> {
+    drop(chan);
+    streams.into_iter().map(|stream| {
+      stream.map(|(_, event, data)| format!("{} {}", event, data.get("id").unwrap().as_str().unwrap())).collect().wait().unwrap()
+    }).collect()
+  }
+
+  #[test]
+  fn routes_messages_by_id() {
+    let chan = MemoryChannel::new();
+    let all = listen(&chan, None, json!({})).unwrap();
+    let one = listen(&chan, Some("1"), json!({})).unwrap();
+    let two = listen(&chan, Some("2"), json!({})).unwrap();
+    chan.send_about("1", "patched", &obj(json!({"id": "1"})));
+    chan.send_about("3", "created", &obj(json!({"id": "3"})));
+    chan.send("reset", &obj(json!({"id": "all"})));
+    assert_eq!(received(chan, vec![all, one, two]), vec![
+      vec!["patched 1", "created 3", "reset all"],
+      vec!["patched 1", "reset all"],
+      vec!["reset all"],
+    ]);
+  }
+
+  #[test]
+  fn filters_messages_by_params() {
+    let chan = MemoryChannel::filtered(&["color", "age", "owner_id"]).with_path_params(&["owner_id"]);
+    let grey = listen(&chan, None, json!({"color": "grey", "token": "abc"})).unwrap();
+    let old = listen(&chan, None, json!({"age": {"$gte": "10"}})).unwrap();
+    assert!(listen(&chan, None, json!({"age": {"$nope": "10"}})).is_err());
+    assert!(listen(&chan, None, json!({"token": {"$nope": "10"}})).is_ok());
+    // the owner id comes from the path, so it isn't a filter even though it's declared as one,
+    // whether the client joins through `handle` or straight through `join` or `rejoin`
+    let params = obj(json!({"owner_id": "7"}));
+    let req = Request::new("/users/:owner_id/cats".to_string(), Method::Listen, None, JsonObject::new(), params.clone());
+    let owned = take_event_stream(chan.handle(req).wait().unwrap()).ok().unwrap();
+    assert!(chan.authorize(&None, &obj(json!({"owner_id": {"$nope": "7"}}))).wait().is_ok());
+    let (sender, joined) = ::channel::new_queue(10, Overflow::DropOldest);
+    chan.join(sender, None, params.clone());
+    chan.send_about("1", "created", &obj(json!({"id": "1", "color": "grey", "age": 3})));
+    let (sender, rejoined) = ::channel::new_queue(10, Overflow::DropOldest);
+    chan.rejoin(sender, None, params, "0");
+    chan.send_about("2", "created", &obj(json!({"id": "2", "color": "orange", "age": 12})));
+    assert_eq!(received(chan, vec![grey, old, owned, joined.boxed(), rejoined.boxed()]), vec![
+      vec!["created 1"],
+      vec!["created 2"],
+      vec!["created 1", "created 2"],
+      vec!["created 1", "created 2"],
+      vec!["created 1", "created 2"],
+    ]);
+  }
+
+  #[test]
+  fn prunes_disconnected_clients() {
+    let chan = MemoryChannel::new();
+    let all = listen(&chan, None, json!({})).unwrap();
+    let one = listen(&chan, Some("1"), json!({})).unwrap();
+    drop(one);
+    chan.send_about("1", "patched", &obj(json!({"id": "1"})));
+    assert!(chan.rooms.lock().unwrap().ids.is_empty());
+    drop(all);
+    drop(listen(&chan, Some("2"), json!({})).unwrap());
+    assert!(chan.rooms.lock().unwrap().collection.is_empty());
+  }
+
+  #[test]
+  fn replays_missed_messages() {
+    let chan = MemoryChannel::new().with_replay_limit(2);
+    chan.send_about("1", "created", &obj(json!({"id": "1"})));
+    chan.send_about("2", "created", &obj(json!({"id": "2"})));
+    chan.send_about("1", "patched", &obj(json!({"id": "1"})));
+    chan.send_about("2", "patched", &obj(json!({"id": "2"})));
+    let mut req = Request::new("/cats".to_string(), Method::Listen, Some("1".to_string()), JsonObject::new(), JsonObject::new());
+    req.set_last_event_id(Some("1".to_string()));
+    let one = take_event_stream(chan.handle(req).wait().unwrap()).ok().unwrap();
+    drop(chan);
+    let events: Vec<(Option<u64>, String)> = one.map(|(id, event, _)| (id, event)).collect().wait().unwrap();
+    // the first patch is the only missed message about cat 1 that's still in the buffer
+    assert_eq!(events, vec![(Some(3), "patched".to_string())]);
+  }
+
+  #[test]
+  fn refuses_unauthorized_clients() {
+    let chan = MemoryChannel::new().with_authorization(|id: &Option<String>, params: &JsonObject| {
+      if params.get("token").is_none() {
+        return Error::unauthorized("please provide a token");
+      }
+      if id == &Some("secret".to_string()) {
+        return Error::forbidden("you can't listen to this cat");
+      }
+      ok(()).boxed()
+    });
+    assert_eq!(listen(&chan, None, json!({})).err().unwrap().status_code(), 401);
+    assert_eq!(listen(&chan, Some("secret"), json!({"token": "abc"})).err().unwrap().status_code(), 403);
+    let all = listen(&chan, None, json!({"token": "abc"})).unwrap();
+    chan.send_about("1", "created", &obj(json!({"id": "1"})));
+    assert_eq!(chan.rooms.lock().unwrap().ids.len(), 0);
+    assert_eq!(received(chan, vec![all]), vec![vec!["created 1"]]);
+  }
+
+  #[test]
+  fn filters_events_per_client() {
+    let chan = MemoryChannel::new().with_event_filter(|params: &JsonObject, event, mut data: JsonObject| {
+      if params.get("admin").is_none() {
+        data.remove("secret");
+      }
+      Some((event, data))
+    });
+    let admin = listen(&chan, None, json!({"admin": "yes"})).unwrap();
+    let user = listen(&chan, None, json!({})).unwrap();
+    chan.send_about("1", "created", &obj(json!({"id": "1", "secret": "shh"})));
+    drop(chan);
+    let secrets: Vec<Vec<bool>> = vec![admin, user].into_iter().map(|stream| {
+      stream.map(|(_, _, data)| data.contains_key("secret")).collect().wait().unwrap()
+    }).collect();
+    assert_eq!(secrets, vec![vec![true], vec![false]]);
+  }
+
+  #[test]
+  fn tracks_presence() {
+    let chan = MemoryChannel::new().with_presence(|params: &JsonObject| {
+      let mut presence = JsonObject::new();
+      presence.insert("id".to_string(), params.get("user").unwrap().clone());
+      presence
+    });
+    let tom = listen(&chan, Some("1"), json!({"user": "tom", "token": "shh"})).unwrap();
+    let jerry = listen(&chan, Some("1"), json!({"user": "jerry"})).unwrap();
+    let spike = listen(&chan, Some("1"), json!({"user": "spike"})).unwrap();
+    assert_eq!(chan.presence(&Some("1".to_string())), vec![
+      obj(json!({"id": "tom"})),
+      obj(json!({"id": "jerry"})),
+      obj(json!({"id": "spike"})),
+    ]);
+    assert!(chan.presence(&None).is_empty());
+    drop(jerry);
+    assert_eq!(chan.presence(&Some("1".to_string())).len(), 2);
+    assert_eq!(received(chan, vec![tom, spike]), vec![
+      vec!["join jerry", "join spike", "leave jerry"],
+      vec!["leave jerry"],
+    ]);
+  }
+
+  #[test]
+  fn announces_only_authorized_clients() {
+    let chan = MemoryChannel::new().with_authorization(|_: &Option<String>, params: &JsonObject| {
+      match params.get("token") {
+        Some(&JsonValue::String(ref token)) if token == "slow" => empty().boxed(),
+        Some(_) => ok(()).boxed(),
+        None => Error::unauthorized("please provide a token"),
+      }
+    }).with_presence(|params: &JsonObject| {
+      let mut presence = JsonObject::new();
+      presence.insert("id".to_string(), params.get("user").unwrap().clone());
+      presence
+    });
+    let tom = listen(&chan, None, json!({"user": "tom", "token": "abc"})).unwrap();
+    assert!(listen(&chan, None, json!({"user": "mallory"})).is_err());
+    let req = Request::new("/cats".to_string(), Method::Listen, None, JsonObject::new(), obj(json!({"user": "jerry", "token": "slow"})));
+    let pending = chan.handle(req);
+    assert_eq!(chan.presence(&None), vec![obj(json!({"id": "tom"}))]);
+    drop(pending);
+    assert_eq!(received(chan, vec![tom]), vec![Vec::<String>::new()]);
+  }
 }
@@ -0,0 +1,397 @@
+use futures::future::{Future, BoxFuture, ok, err, result};
+use {JsonValue, ErrorKind, Adapter, JsonObject};
+use super::store::{Store, Change, IdStrategy};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use serde_json;
+
+// don't bother compacting tiny logs
+const MIN_COMPACT_ENTRIES: usize = 1000;
+
+fn std_error(kind: ErrorKind, err_str: &str) -> (ErrorKind, JsonValue) {
+  let val = json!({
+    "error": {
+      "type": kind.as_string(),
+      "message": err_str.to_string(),
+    }
+  });
+  (kind, val)
+}
+
+/**
+An adapter like `MemoryAdapter` that persists its data to a file, so it survives restarts.
+
+Every change is appended to the file as a line of JSON. When the adapter is opened, it replays the
+file to recover its data and the counter for new ids. Once the log gets much longer than the
+number of records, it's compacted by rewriting it with one line per record.
+
+Each change is synced to disk before the adapter replies, so a change a client has been told about
+survives a crash or a power failure. That makes every write wait for the disk.
+
+Since it keeps everything in memory and writes on the event loop, this is meant for development
+and small apps, not for large amounts of data. To switch from a `MemoryAdapter`, replace
+`MemoryAdapter::new()` with `FileAdapter::open("cats.jsonl").unwrap()`.
+*/
+pub struct FileAdapter {
+  inside: Mutex<Inside>,
+}
+
+struct Inside {
+  store: Store,
+  path: PathBuf,
+  file: File,
+  // the number of lines in the log
+  entries: usize,
+  // the length of the log up to the end of its last complete line
+  len: u64,
+  // set when `file` might have part of a line at the end, or might not be the file at `path`
+  // anymore, so it has to be reopened before the next write
+  damaged: bool,
+}
+
+fn change_to_line(change: &Change) -> String {
+  let mut line = json!({
+    "last_num": change.last_num,
+    "id": change.id,
+    "record": change.record,
+  }).to_string();
+  line.push('\n');
+  line
+}
+
+fn line_to_change(line: &str) -> Option<Change> {
+  let mut obj: JsonObject = match serde_json::from_str(line) {
+    Ok(o) => o,
+    Err(_) => return None,
+  };
+  let last_num = match obj.get("last_num").and_then(|n| n.as_i64()) {
+    Some(n) => n,
+    None => return None,
+  };
+  let id = match obj.remove("id") {
+    Some(JsonValue::String(s)) => Some(s),
+    _ => None,
+  };
+  let record = match obj.remove("record") {
+    Some(JsonValue::Object(o)) => Some(o),
+    _ => None,
+  };
+  Some(Change {
+    last_num: last_num,
+    id: id,
+    record: record,
+  })
+}
+
+fn open_log(path: &Path) -> io::Result<File> {
+  OpenOptions::new().create(true).append(true).open(path)
+}
+
+// makes a rename into the file's directory survive a power failure
+fn sync_dir(path: &Path) -> io::Result<()> {
+  if cfg!(unix) {
+    let dir = match path.parent() {
+      Some(dir) if dir != Path::new("") => dir,
+      _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+  } else {
+    Ok(())
+  }
+}
+
+fn ends_with_newline(path: &Path, len: u64) -> io::Result<bool> {
+  if len == 0 {
+    return Ok(true);
+  }
+  let mut file = File::open(path)?;
+  file.seek(SeekFrom::End(-1))?;
+  let mut last = [0; 1];
+  file.read_exact(&mut last)?;
+  Ok(last[0] == b'\n')
+}
+
+impl FileAdapter {
+  /**
+  Opens the adapter, creating the file at `path` if it doesn't exist yet. Returns an error if the
+  file can't be read, or if a line other than the last one is corrupted. A corrupted last line is
+  assumed to be a write that was interrupted by a crash, and is dropped.
+  */
+  pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileAdapter> {
+    let path = path.as_ref().to_path_buf();
+    let mut store = Store::new();
+    let mut entries = 0;
+    // the line number of a line that couldn't be read
+    let mut corrupted = None;
+    if path.exists() {
+      let reader = BufReader::new(File::open(&path)?);
+      for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim() == "" {
+          continue;
+        }
+        if let Some(n) = corrupted {
+          return Err(io::Error::new(io::ErrorKind::InvalidData, format!("corrupted line {} in {}", n, path.display())));
+        }
+        match line_to_change(&line) {
+          Some(change) => store.apply(change),
+          None => corrupted = Some(i + 1),
+        }
+        entries += 1;
+      }
+    }
+    let file = open_log(&path)?;
+    let len = file.metadata()?.len();
+    // a line that was cut off right before its newline still parses, but the next one would be
+    // written on the same line
+    let torn_write = corrupted.is_some() || !ends_with_newline(&path, len)?;
+    let mut inside = Inside {
+      store: store,
+      file: file,
+      path: path,
+      entries: entries,
+      len: len,
+      damaged: false,
+    };
+    if torn_write {
+      inside.compact()?;
+    } else {
+      inside.maybe_compact()?;
+    }
+    Ok(FileAdapter {
+      inside: Mutex::new(inside),
+    })
+  }
+
+  /// Sets how posted objects get their ids. Defaults to `IdStrategy::Sequential`.
+  pub fn with_ids(mut self, ids: IdStrategy) -> FileAdapter {
+    self.inside.get_mut().unwrap().store.ids = ids;
+    self
+  }
+
+  /// Sets the field objects keep their id in, like `"_id"`. Defaults to `"id"`.
+  pub fn with_id_field(mut self, field: &str) -> FileAdapter {
+    self.inside.get_mut().unwrap().store.id_field = field.to_string();
+    self
+  }
+
+  /// Rewrites the file with one line per record, dropping the history of changes.
+  pub fn compact(&self) -> io::Result<()> {
+    self.inside.lock().unwrap().compact()
+  }
+}
+
+impl Inside {
+  fn maybe_compact(&mut self) -> io::Result<()> {
+    if self.entries > MIN_COMPACT_ENTRIES && self.entries > 2 * self.store.datastore.len() {
+      self.compact()
+    } else {
+      Ok(())
+    }
+  }
+
+  fn compact(&mut self) -> io::Result<()> {
+    let tmp_path = PathBuf::from(format!("{}.compact", self.path.display()));
+    let mut len = 0;
+    {
+      let mut tmp = File::create(&tmp_path)?;
+      // records the id counter even if there are no records
+      let first = change_to_line(&Change { last_num: self.store.last_num, id: None, record: None });
+      tmp.write_all(first.as_bytes())?;
+      len += first.len() as u64;
+      for (id, record) in self.store.datastore.iter() {
+        let change = Change {
+          last_num: self.store.last_num,
+          id: Some(id.clone()),
+          record: Some(record.clone()),
+        };
+        let line = change_to_line(&change);
+        tmp.write_all(line.as_bytes())?;
+        len += line.len() as u64;
+      }
+      tmp.sync_all()?;
+    }
+    fs::rename(&tmp_path, &self.path)?;
+    self.entries = self.store.datastore.len() + 1;
+    self.len = len;
+    // `file` is the old log now, so writing to it would lose changes
+    self.damaged = true;
+    sync_dir(&self.path)?;
+    self.file = open_log(&self.path)?;
+    self.damaged = false;
+    Ok(())
+  }
+
+  // writes a line to the log, making sure a failed write doesn't leave part of a line behind
+  fn append(&mut self, change: &Change) -> io::Result<()> {
+    if self.damaged {
+      let file = open_log(&self.path)?;
+      file.set_len(self.len)?;
+      self.file = file;
+      self.damaged = false;
+    }
+    let line = change_to_line(change);
+    if let Err(e) = self.file.write_all(line.as_bytes()).and_then(|_| self.file.sync_data()) {
+      self.damaged = true;
+      if self.file.set_len(self.len).is_ok() {
+        self.damaged = false;
+      }
+      return Err(e);
+    }
+    self.len += line.len() as u64;
+    Ok(())
+  }
+
+  // writes the change to the log before applying it, so we never reply with unsaved data
+  fn commit(&mut self, res: Result<(Change, JsonObject), (ErrorKind, JsonValue)>) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let (change, reply) = match res {
+      Ok(t) => t,
+      Err(e) => return err(e).boxed(),
+    };
+    if self.append(&change).is_err() {
+      return err(std_error(ErrorKind::ServerError, "couldn't write to the database file")).boxed();
+    }
+    self.store.apply(change);
+    self.entries += 1;
+    // the change is saved either way, and the next write reopens the log if compacting left it
+    // pointing at the old file
+    if self.maybe_compact().is_err() {
+      return err(std_error(ErrorKind::ServerError, "saved the change, but couldn't compact the database file")).boxed();
+    }
+    ok(reply).boxed()
+  }
+}
+
+impl Adapter for FileAdapter {
+  /// supports the same filtering, sorting and pagination as `MemoryAdapter`
+  fn list(&self, params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    result(self.inside.lock().unwrap().store.list(params)).boxed()
+  }
+
+  fn get(&self, id: &str, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    result(self.inside.lock().unwrap().store.get(id)).boxed()
+  }
+
+  fn post(&self, data: &JsonObject, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let mut inside = self.inside.lock().unwrap();
+    let res = inside.store.post(data);
+    inside.commit(res)
+  }
+
+  fn patch(&self, id: &str, data: &JsonObject, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let mut inside = self.inside.lock().unwrap();
+    let res = inside.store.patch(id, data);
+    inside.commit(res)
+  }
+
+  fn json_patch(&self, id: &str, ops: &[JsonValue], _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let mut inside = self.inside.lock().unwrap();
+    let res = inside.store.json_patch(id, ops);
+    inside.commit(res)
+  }
+
+  fn update(&self, id: &str, data: &JsonObject, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let mut inside = self.inside.lock().unwrap();
+    let res = inside.store.update(id, data);
+    inside.commit(res)
+  }
+
+  fn delete(&self, id: &str, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let mut inside = self.inside.lock().unwrap();
+    let res = inside.store.delete(id);
+    inside.commit(res)
+  }
+
+  fn id_field(&self) -> String {
+    self.inside.lock().unwrap().store.id_field.clone()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use obj;
+  use std::env;
+  use uuid::Uuid;
+
+  fn temp_path() -> PathBuf {
+    env::temp_dir().join(format!("backtalk-{}.jsonl", Uuid::new_v4()))
+  }
+
+  #[test]
+  fn recovers_data_and_ids() {
+    let path = temp_path();
+    {
+      let adapter = FileAdapter::open(&path).unwrap();
+      adapter.post(&obj(json!({"name": "Tom"})), &JsonObject::new()).wait().unwrap();
+      adapter.post(&obj(json!({"name": "Sam"})), &JsonObject::new()).wait().unwrap();
+      adapter.patch("1", &obj(json!({"age": 3})), &JsonObject::new()).wait().unwrap();
+      adapter.delete("2", &JsonObject::new()).wait().unwrap();
+    }
+    let adapter = FileAdapter::open(&path).unwrap();
+    let tom = adapter.get("1", &JsonObject::new()).wait().unwrap();
+    assert_eq!(tom.get("age").unwrap(), 3);
+    assert!(adapter.get("2", &JsonObject::new()).wait().is_err());
+    let new_cat = adapter.post(&obj(json!({"name": "Max"})), &JsonObject::new()).wait().unwrap();
+    assert_eq!(new_cat.get("id").unwrap(), "3");
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn compacts_and_drops_torn_writes() {
+    let path = temp_path();
+    {
+      let adapter = FileAdapter::open(&path).unwrap();
+      adapter.post(&obj(json!({"name": "Tom"})), &JsonObject::new()).wait().unwrap();
+      adapter.post(&obj(json!({"name": "Sam"})), &JsonObject::new()).wait().unwrap();
+      adapter.delete("2", &JsonObject::new()).wait().unwrap();
+      adapter.compact().unwrap();
+    }
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"last_num\": 4, \"id\"").unwrap();
+    let adapter = FileAdapter::open(&path).unwrap();
+    assert!(adapter.get("1", &JsonObject::new()).wait().is_ok());
+    let new_cat = adapter.post(&obj(json!({"name": "Max"})), &JsonObject::new()).wait().unwrap();
+    assert_eq!(new_cat.get("id").unwrap(), "3");
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn repairs_the_log_after_failed_writes() {
+    let path = temp_path();
+    {
+      let adapter = FileAdapter::open(&path).unwrap();
+      adapter.post(&obj(json!({"name": "Tom"})), &JsonObject::new()).wait().unwrap();
+      {
+        // as if a write failed partway, and so did cutting it off
+        let mut inside = adapter.inside.lock().unwrap();
+        inside.file.write_all(b"{\"last_num\": 2, \"id\"").unwrap();
+        inside.damaged = true;
+      }
+      adapter.post(&obj(json!({"name": "Sam"})), &JsonObject::new()).wait().unwrap();
+    }
+    let adapter = FileAdapter::open(&path).unwrap();
+    assert_eq!(adapter.get("2", &JsonObject::new()).wait().unwrap().get("name").unwrap(), "Sam");
+    fs::remove_file(&path).unwrap();
+
+    // a change that was cut off right before its newline is kept, on a line of its own
+    OpenOptions::new().create(true).append(true).open(&path).unwrap().write_all(b"{\"last_num\": 1, \"id\": \"1\", \"record\": {}}").unwrap();
+    let adapter = FileAdapter::open(&path).unwrap();
+    let max = adapter.post(&obj(json!({"name": "Max"})), &JsonObject::new()).wait().unwrap();
+    drop(adapter);
+    let adapter = FileAdapter::open(&path).unwrap();
+    assert!(adapter.get("1", &JsonObject::new()).wait().is_ok());
+    assert_eq!(adapter.get(max.get("id").unwrap().as_str().unwrap(), &JsonObject::new()).wait().unwrap(), max);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn reports_corrupted_lines() {
+    let path = temp_path();
+    fs::write(&path, "{\"last_num\": 1, \"id\": \"1\", \"record\": {}}\nnope\n{\"last_num\": 1, \"id\": null}\n").unwrap();
+    let e = FileAdapter::open(&path).err().unwrap();
+    assert_eq!(e.to_string(), format!("corrupted line 2 in {}", path.display()));
+    fs::remove_file(&path).unwrap();
+  }
+}
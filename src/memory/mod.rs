@@ -1,5 +1,11 @@
+mod store;
+pub use self::store::IdStrategy;
+
 mod adapter;
 pub use self::adapter::MemoryAdapter;
 
+mod file;
+pub use self::file::FileAdapter;
+
 mod channel;
 pub use self::channel::MemoryChannel;
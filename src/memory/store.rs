@@ -0,0 +1,170 @@
+use {JsonValue, ErrorKind, JsonObject};
+use query::{Query, ListOptions};
+use patch;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+fn std_error(kind: ErrorKind, err_str: &str) -> (ErrorKind, JsonValue) {
+  let val = json!({
+    "error": {
+      "type": kind.as_string(),
+      "message": err_str.to_string(),
+    }
+  });
+  (kind, val)
+}
+
+/// A single change to a `Store`, which `FileAdapter` also writes to its log.
+#[derive(Debug, Clone)]
+pub struct Change {
+  pub last_num: i64,
+  pub id: Option<String>,
+  /// The new record, or `None` if the record was deleted.
+  pub record: Option<JsonObject>,
+}
+
+/**
+How `MemoryAdapter` and `FileAdapter` pick the id of a posted object. Posting an object with an id
+that's already taken is a `Conflict` error, whichever strategy is used.
+*/
+pub enum IdStrategy {
+  /// `"1"`, `"2"`, `"3"` and so on. This is the default.
+  Sequential,
+  /// Random UUIDv4s, like `"0e4f8b2a-..."`.
+  Uuid,
+  /// The id the client sent in the posted data, which has to be a string. Posting without one is a
+  /// `BadRequest` error.
+  Client,
+  /// Ids made by a function from the posted data.
+  Custom(Box<Fn(&JsonObject) -> String + Send + Sync>),
+}
+
+/**
+The datastore shared by `MemoryAdapter` and `FileAdapter`.
+
+Operations that change data return a `Change` instead of applying it, so `FileAdapter` can
+persist the change before applying it with `apply`.
+*/
+pub struct Store {
+  pub datastore: HashMap<String, JsonObject>,
+  pub last_num: i64,
+  pub ids: IdStrategy,
+  /// The field objects keep their id in, `"id"` by default.
+  pub id_field: String,
+}
+
+type StoreResult<T> = Result<T, (ErrorKind, JsonValue)>;
+
+impl Store {
+  pub fn new() -> Store {
+    Store {
+      datastore: HashMap::new(),
+      last_num: 0,
+      ids: IdStrategy::Sequential,
+      id_field: "id".to_string(),
+    }
+  }
+
+  pub fn list(&self, params: &JsonObject) -> StoreResult<JsonObject> {
+    let query = Query::parse(params)?;
+    let mut options = ListOptions::parse(params)?;
+    options.id_field = self.id_field.clone();
+    let res: Vec<JsonObject> = self.datastore
+      .iter()
+      .map(|(_, item)| item)
+      .filter(|item| query.matches(item))
+      .cloned()
+      .collect();
+    Ok(options.paginate(res).to_object())
+  }
+
+  pub fn get(&self, id: &str) -> StoreResult<JsonObject> {
+    match self.datastore.get(id) {
+      Some(val) => Ok(val.clone()),
+      None => Err(std_error(ErrorKind::NotFound, "couldn't find object with that id")),
+    }
+  }
+
+  pub fn post(&self, data: &JsonObject) -> StoreResult<(Change, JsonObject)> {
+    let mut last_num = self.last_num;
+    let id_str = match self.ids {
+      IdStrategy::Sequential => loop {
+        // skips the ids clients already picked with `update`
+        last_num = match last_num.checked_add(1) {
+          Some(num) => num,
+          None => return Err(std_error(ErrorKind::ServerError, "ran out of sequential ids")),
+        };
+        if !self.datastore.contains_key(&last_num.to_string()) {
+          break last_num.to_string();
+        }
+      },
+      IdStrategy::Uuid => Uuid::new_v4().to_string(),
+      IdStrategy::Client => match data.get(&self.id_field) {
+        Some(&JsonValue::String(ref id)) => id.clone(),
+        _ => return Err(std_error(ErrorKind::BadRequest, &format!("`{}` has to be a string", self.id_field))),
+      },
+      IdStrategy::Custom(ref f) => f(data),
+    };
+    if self.datastore.contains_key(&id_str) {
+      return Err(std_error(ErrorKind::Conflict, "an object with that id already exists"));
+    }
+    let mut data = data.clone(); // TODO remove clones?
+    data.insert(self.id_field.clone(), JsonValue::String(id_str.clone()));
+    Ok((Change { last_num: last_num, id: Some(id_str), record: Some(data.clone()) }, data))
+  }
+
+  pub fn patch(&self, id: &str, data: &JsonObject) -> StoreResult<(Change, JsonObject)> {
+    if let Some(_) = data.get(&self.id_field) {
+      return Err(std_error(ErrorKind::BadRequest, "can't update id"));
+    }
+    let mut dbdata = match self.datastore.get(id) {
+      Some(val) => val.clone(),
+      None => return Err(std_error(ErrorKind::NotFound, "couldn't find object with that id")),
+    };
+    patch::merge(&mut dbdata, data);
+    Ok((Change { last_num: self.last_num, id: Some(id.to_string()), record: Some(dbdata.clone()) }, dbdata))
+  }
+
+  pub fn json_patch(&self, id: &str, ops: &[JsonValue]) -> StoreResult<(Change, JsonObject)> {
+    let dbdata = match self.datastore.get(id) {
+      Some(val) => patch::apply(val, ops)?,
+      None => return Err(std_error(ErrorKind::NotFound, "couldn't find object with that id")),
+    };
+    if dbdata.get(&self.id_field) != Some(&JsonValue::String(id.to_string())) {
+      return Err(std_error(ErrorKind::BadRequest, "can't update id"));
+    }
+    Ok((Change { last_num: self.last_num, id: Some(id.to_string()), record: Some(dbdata.clone()) }, dbdata))
+  }
+
+  pub fn update(&self, id: &str, data: &JsonObject) -> StoreResult<(Change, JsonObject)> {
+    match data.get(&self.id_field) {
+      Some(&JsonValue::String(ref data_id)) if data_id == id => (),
+      Some(_) => return Err(std_error(ErrorKind::BadRequest, "id in data doesn't match the URL")),
+      None => (),
+    }
+    let mut data = data.clone();
+    data.insert(self.id_field.clone(), JsonValue::String(id.to_string()));
+    Ok((Change { last_num: self.last_num, id: Some(id.to_string()), record: Some(data.clone()) }, data))
+  }
+
+  pub fn delete(&self, id: &str) -> StoreResult<(Change, JsonObject)> {
+    let mut data = JsonObject::new();
+    data.insert(self.id_field.clone(), JsonValue::String(id.to_string()));
+    Ok((Change { last_num: self.last_num, id: Some(id.to_string()), record: None }, data))
+  }
+
+  pub fn apply(&mut self, change: Change) {
+    if change.last_num > self.last_num {
+      self.last_num = change.last_num;
+    }
+    match (change.id, change.record) {
+      (Some(id), Some(record)) => {
+        self.datastore.insert(id, record);
+      },
+      (Some(id), None) => {
+        self.datastore.remove(&id);
+      },
+      (None, _) => (),
+    }
+  }
+}
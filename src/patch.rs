@@ -0,0 +1,241 @@
+/*!
+JSON Merge Patch (RFC 7396) and JSON Patch (RFC 6902), for adapters implementing `patch`.
+
+A `PATCH` request's data is a merge patch by default: objects in the patch are merged into the
+stored object recursively, `null` removes a key, and anything else replaces the old value.
+
+```json
+{"owner": {"name": "Jon", "phone": null}}
+```
+
+Resources made with `Resource::accept_json_patch` also take `PATCH` requests sent with
+`Content-Type: application/json-patch+json`, which are a list of JSON Patch operations instead.
+Adapters get them with `Adapter::json_patch`, and other handlers refuse them (see
+`Handler::accepts_json_patch`):
+
+```json
+[{"op": "replace", "path": "/owner/name", "value": "Jon"}, {"op": "remove", "path": "/owner/phone"}]
+```
+
+Operations are applied in order, and if any of them fails, including a `test` operation, none of
+them are.
+*/
+
+use {JsonValue, JsonObject, ErrorKind};
+
+fn std_error(kind: ErrorKind, err_str: &str) -> (ErrorKind, JsonValue) {
+  let val = json!({
+    "error": {
+      "type": kind.as_string(),
+      "message": err_str.to_string(),
+    }
+  });
+  (kind, val)
+}
+
+/// Applies a JSON Merge Patch to `target`.
+pub fn merge(target: &mut JsonObject, patch: &JsonObject) {
+  for (key, val) in patch.iter() {
+    match val {
+      &JsonValue::Null => {
+        target.remove(key);
+      },
+      &JsonValue::Object(ref patch) => {
+        let mut merged = match target.remove(key) {
+          Some(JsonValue::Object(obj)) => obj,
+          _ => JsonObject::new(),
+        };
+        merge(&mut merged, patch);
+        target.insert(key.clone(), JsonValue::Object(merged));
+      },
+      val => {
+        target.insert(key.clone(), val.clone());
+      },
+    }
+  }
+}
+
+/**
+Applies a list of JSON Patch operations to a copy of `target`, and returns the patched copy. If an
+operation is invalid or fails, returns a `BadRequest` error saying which one, and `target` is left
+as it was.
+*/
+pub fn apply(target: &JsonObject, ops: &[JsonValue]) -> Result<JsonObject, (ErrorKind, JsonValue)> {
+  let mut doc = JsonValue::Object(target.clone());
+  for (i, op) in ops.iter().enumerate() {
+    if let Err(e) = apply_op(&mut doc, op) {
+      return Err(std_error(ErrorKind::BadRequest, &format!("JSON Patch operation {} failed: {}", i, e)));
+    }
+  }
+  match doc {
+    JsonValue::Object(obj) => Ok(obj),
+    _ => Err(std_error(ErrorKind::BadRequest, "JSON Patch has to leave an object")),
+  }
+}
+
+fn apply_op(doc: &mut JsonValue, op: &JsonValue) -> Result<(), String> {
+  let op = match op {
+    &JsonValue::Object(ref op) => op,
+    _ => return Err("operations have to be objects".to_string()),
+  };
+  let path = pointer(op, "path")?;
+  let value = || op.get("value").cloned().ok_or_else(|| "missing `value`".to_string());
+  match op.get("op").and_then(|o| o.as_str()) {
+    Some("add") => add(doc, &path, value()?),
+    Some("remove") => remove(doc, &path).map(|_| ()),
+    Some("replace") => {
+      let slot = get_mut(doc, &path).ok_or_else(|| "`path` doesn't exist".to_string())?;
+      *slot = value()?;
+      Ok(())
+    },
+    Some("move") => {
+      let from = pointer(op, "from")?;
+      if path.len() > from.len() && path[..from.len()] == from[..] {
+        return Err("can't move a value into itself".to_string());
+      }
+      let val = remove(doc, &from)?;
+      add(doc, &path, val)
+    },
+    Some("copy") => {
+      let from = pointer(op, "from")?;
+      let val = get_mut(doc, &from).map(|v| v.clone()).ok_or_else(|| "`from` doesn't exist".to_string())?;
+      add(doc, &path, val)
+    },
+    Some("test") => {
+      match get_mut(doc, &path) {
+        Some(val) if *val == value()? => Ok(()),
+        _ => Err("test failed".to_string()),
+      }
+    },
+    _ => Err("unknown `op`".to_string()),
+  }
+}
+
+// parses the JSON Pointer in `op[key]` into its unescaped tokens
+fn pointer(op: &JsonObject, key: &str) -> Result<Vec<String>, String> {
+  let pointer = match op.get(key) {
+    Some(&JsonValue::String(ref p)) => p,
+    _ => return Err(format!("missing `{}`", key)),
+  };
+  if pointer == "" {
+    return Ok(Vec::new());
+  }
+  if !pointer.starts_with('/') {
+    return Err(format!("`{}` has to start with `/`", key));
+  }
+  Ok(pointer[1..].split('/').map(|t| t.replace("~1", "/").replace("~0", "~")).collect())
+}
+
+fn index(token: &str, len: usize) -> Option<usize> {
+  if token == "" || (token.len() > 1 && token.starts_with('0')) || !token.chars().all(|c| c.is_digit(10)) {
+    return None;
+  }
+  token.parse().ok().and_then(|i| if i < len { Some(i) } else { None })
+}
+
+fn get_mut<'a>(doc: &'a mut JsonValue, path: &[String]) -> Option<&'a mut JsonValue> {
+  let (token, rest) = match path.split_first() {
+    Some(t) => t,
+    None => return Some(doc),
+  };
+  let child = match doc {
+    &mut JsonValue::Object(ref mut obj) => obj.get_mut(token),
+    &mut JsonValue::Array(ref mut arr) => {
+      let len = arr.len();
+      index(token, len).map(move |i| &mut arr[i])
+    },
+    _ => None,
+  };
+  child.and_then(|c| get_mut(c, rest))
+}
+
+fn add(doc: &mut JsonValue, path: &[String], val: JsonValue) -> Result<(), String> {
+  let (last, parent) = match path.split_last() {
+    Some(t) => t,
+    None => {
+      *doc = val;
+      return Ok(());
+    },
+  };
+  match get_mut(doc, parent) {
+    Some(&mut JsonValue::Object(ref mut obj)) => {
+      obj.insert(last.clone(), val);
+      Ok(())
+    },
+    Some(&mut JsonValue::Array(ref mut arr)) => {
+      let len = arr.len();
+      // `-` appends, and an index can also be one past the end
+      let i = if last == "-" { Some(len) } else { index(last, len + 1) };
+      match i {
+        Some(i) => {
+          arr.insert(i, val);
+          Ok(())
+        },
+        None => Err("array index out of bounds".to_string()),
+      }
+    },
+    _ => Err("`path` doesn't exist".to_string()),
+  }
+}
+
+fn remove(doc: &mut JsonValue, path: &[String]) -> Result<JsonValue, String> {
+  let (last, parent) = match path.split_last() {
+    Some(t) => t,
+    None => return Err("can't remove the whole object".to_string()),
+  };
+  let removed = match get_mut(doc, parent) {
+    Some(&mut JsonValue::Object(ref mut obj)) => obj.remove(last),
+    Some(&mut JsonValue::Array(ref mut arr)) => {
+      let len = arr.len();
+      index(last, len).map(|i| arr.remove(i))
+    },
+    _ => None,
+  };
+  removed.ok_or_else(|| "`path` doesn't exist".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use obj;
+
+  #[test]
+  fn merges_recursively() {
+    let mut cat = obj(json!({"name": "Tom", "owner": {"name": "Jon", "phone": "555"}, "tags": ["grey"]}));
+    merge(&mut cat, &obj(json!({"owner": {"phone": null, "city": "Paris"}, "tags": ["black"], "age": 3})));
+    assert_eq!(JsonValue::Object(cat), json!({
+      "name": "Tom",
+      "owner": {"name": "Jon", "city": "Paris"},
+      "tags": ["black"],
+      "age": 3,
+    }));
+  }
+
+  #[test]
+  fn applies_json_patch() {
+    let cat = obj(json!({"name": "Tom", "tags": ["grey", "fluffy"], "owner": {"name": "Jon"}}));
+    let ops = json!([
+      {"op": "test", "path": "/name", "value": "Tom"},
+      {"op": "add", "path": "/tags/-", "value": "old"},
+      {"op": "remove", "path": "/tags/0"},
+      {"op": "replace", "path": "/owner/name", "value": "Liz"},
+      {"op": "copy", "from": "/owner", "path": "/vet"},
+      {"op": "move", "from": "/name", "path": "/a~1b"},
+    ]);
+    let patched = apply(&cat, ops.as_array().unwrap()).unwrap();
+    assert_eq!(JsonValue::Object(patched), json!({
+      "a/b": "Tom",
+      "tags": ["fluffy", "old"],
+      "owner": {"name": "Liz"},
+      "vet": {"name": "Liz"},
+    }));
+
+    let ops = json!([
+      {"op": "replace", "path": "/name", "value": "Sam"},
+      {"op": "test", "path": "/name", "value": "Tom"},
+    ]);
+    assert!(apply(&cat, ops.as_array().unwrap()).is_err());
+    assert!(apply(&cat, json!([{"op": "remove", "path": "/tags/2"}]).as_array().unwrap()).is_err());
+    assert!(apply(&cat, json!([{"op": "move", "from": "/owner", "path": "/owner/pet"}]).as_array().unwrap()).is_err());
+  }
+}
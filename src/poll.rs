@@ -0,0 +1,350 @@
+/*!
+A long-polling transport for `Listen` requests, for clients behind proxies that buffer event streams
+until they close.
+
+A client starts with `GET /cats?poll=` (or `GET /cats/123?poll=`), which goes through the `Server`
+as a `Listen` request, and gets back a cursor right away:
+
+```json
+{"cursor": "5f0c...-0", "events": []}
+```
+
+From then on, it keeps asking for `GET /cats?poll=<cursor>`. The server holds each request until
+the channel sends events or the poll timeout runs out, then answers with the events it got and the
+cursor to use next:
+
+```json
+{"cursor": "5f0c...-1", "events": [{"event": "patched", "data": {...}, "id": 3}]}
+```
+
+Events are queued between polls with the same `Sender` an event stream would use. Polling again
+with the previous cursor sends the last batch again, in case the client never got it. A cursor that
+isn't polled for twice the poll timeout expires, and the client gets a `BadRequest` error and
+should start over. If the channel ends the stream, the reply's cursor is `null`.
+*/
+
+use {JsonValue, JsonObject, Reply, Request, Error, ErrorKind};
+use reply::{EventReceiver, make_reply, take_event_stream};
+use channel::Event;
+use futures::{Future, IntoFuture, Stream, Async, Poll};
+use futures::future::{ok, err, loop_fn, Loop};
+use queryst::parse as query_parse;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Timeout};
+use uuid::Uuid;
+
+fn std_error(kind: ErrorKind, err_str: &str) -> Error {
+  let val = json!({
+    "error": {
+      "type": kind.as_string(),
+      "message": err_str.to_string(),
+    }
+  });
+  Error::new(
+    kind,
+    val
+  )
+}
+
+// only used internally, the `poll` query parameter, if there is one
+pub fn cursor(query: &str) -> Option<String> {
+  match query_parse(query) {
+    Ok(JsonValue::Object(mut q)) => match q.remove("poll") {
+      Some(JsonValue::String(s)) => Some(s),
+      Some(JsonValue::Null) => Some(String::new()),
+      Some(val) => Some(val.to_string()),
+      None => None,
+    },
+    _ => None,
+  }
+}
+
+// a client's subscription, kept between polls
+struct Session {
+  resource: String,
+  id: Option<String>,
+  // taken out while a request is polling it
+  events: Option<EventReceiver>,
+  // the number at the end of the current cursor
+  seq: u64,
+  // the events sent with the current cursor, in case the client polls the previous one again
+  last_batch: Vec<JsonValue>,
+  polled: Instant,
+}
+
+// only used internally, the long-polling sessions of a server, keyed by cursor prefix
+#[derive(Clone)]
+pub struct Sessions {
+  sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl Sessions {
+  // only used internally
+  pub fn new() -> Sessions {
+    Sessions {
+      sessions: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  // only used internally, starts a session with the streaming reply to a `Listen` request, which
+  // expires once it hasn't been polled for twice `timeout`
+  pub fn start<F>(&self, reply: F, timeout: Duration, handle: &Handle) -> Box<Future<Item=Reply, Error=Error>>
+    where F: Future<Item=Reply, Error=Error> + 'static
+  {
+    let sessions = self.clone();
+    let handle = handle.clone();
+    Box::new(reply.map(move |reply| {
+      let req = Request::new(reply.resource().to_string(), reply.method(), reply.id().clone(), JsonObject::new(), JsonObject::new());
+      let events = match take_event_stream(reply) {
+        Ok(events) => events,
+        // not a stream, so there's nothing to poll
+        Err(reply) => return reply,
+      };
+      let key = Uuid::new_v4().to_string();
+      let cursor = format!("{}-0", key);
+      handle.spawn(expire(sessions.clone(), key.clone(), timeout, handle.clone()));
+      sessions.sessions.lock().unwrap().insert(key, Session {
+        resource: req.resource().to_string(),
+        id: req.id().clone(),
+        events: Some(events),
+        seq: 0,
+        last_batch: Vec::new(),
+        polled: Instant::now(),
+      });
+      batch_reply(req, Some(cursor), Vec::new())
+    }))
+  }
+
+  // only used internally, waits for events on the cursor's session, for up to `timeout`. returns
+  // early without any events if `shutdown` resolves.
+  pub fn poll(&self, req: Request, cursor: &str, timeout: Duration, shutdown: Box<Future<Item=(), Error=()>>, handle: &Handle) -> Box<Future<Item=Reply, Error=Error>> {
+    let (key, seq) = match parse_cursor(cursor) {
+      Some(parsed) => parsed,
+      None => return Box::new(err(std_error(ErrorKind::BadRequest, "invalid poll cursor"))),
+    };
+    let events = {
+      let mut sessions = self.sessions.lock().unwrap();
+      let session = match sessions.get_mut(&key) {
+        Some(s) if &s.resource == req.resource() && &s.id == req.id() => s,
+        _ => return Box::new(err(std_error(ErrorKind::BadRequest, "unknown or expired poll cursor, poll again without one"))),
+      };
+      if seq + 1 == session.seq {
+        // the client never got the last batch
+        let batch = session.last_batch.clone();
+        return Box::new(ok(batch_reply(req, Some(format!("{}-{}", key, session.seq)), batch)));
+      }
+      if seq != session.seq {
+        return Box::new(err(std_error(ErrorKind::BadRequest, "unknown or expired poll cursor, poll again without one")));
+      }
+      match session.events.take() {
+        Some(events) => events,
+        None => return Box::new(err(std_error(ErrorKind::BadRequest, "this cursor is already being polled"))),
+      }
+    };
+    let timeout = match Timeout::new(timeout, handle) {
+      Ok(t) => t,
+      Err(_) => return Box::new(err(std_error(ErrorKind::ServerError, "couldn't start the poll timeout"))),
+    };
+    Box::new(NextBatch {
+      sessions: self.clone(),
+      key: key,
+      events: Some(events),
+      batch: Vec::new(),
+      timeout: timeout,
+      shutdown: shutdown,
+    }.map(move |(cursor, batch)| batch_reply(req, cursor, batch)))
+  }
+
+  // puts the stream back in its session once a poll is done with it, returning the next cursor.
+  // `None` means the channel ended the stream, so the session is gone.
+  fn finish(&self, key: &str, events: Option<EventReceiver>, batch: &[JsonValue]) -> Option<String> {
+    let mut sessions = self.sessions.lock().unwrap();
+    if let (Some(events), Some(session)) = (events, sessions.get_mut(key)) {
+      session.events = Some(events);
+      session.polled = Instant::now();
+      if !batch.is_empty() {
+        session.seq += 1;
+        session.last_batch = batch.to_vec();
+      }
+      return Some(format!("{}-{}", key, session.seq));
+    }
+    sessions.remove(key);
+    None
+  }
+}
+
+// drops the session once it hasn't been polled for twice the poll timeout, which also drops its
+// channel listener, even if no other client polls. sessions that are being polled are kept.
+fn expire(sessions: Sessions, key: String, timeout: Duration, handle: Handle) -> Box<Future<Item=(), Error=()>> {
+  let idle = timeout * 2;
+  Box::new(loop_fn(idle, move |wait| {
+    let sessions = sessions.clone();
+    let key = key.clone();
+    Timeout::new(wait, &handle).into_future().flatten().map_err(|_| ()).map(move |_| {
+      let mut sessions = sessions.sessions.lock().unwrap();
+      let polled = match sessions.get(&key) {
+        Some(s) if s.events.is_none() => Instant::now(),
+        Some(s) => s.polled,
+        None => return Loop::Break(()),
+      };
+      match idle.checked_sub(polled.elapsed()) {
+        Some(left) if left > Duration::new(0, 0) => Loop::Continue(left),
+        _ => {
+          sessions.remove(&key);
+          Loop::Break(())
+        },
+      }
+    })
+  }))
+}
+
+fn parse_cursor(cursor: &str) -> Option<(String, u64)> {
+  let split = match cursor.rfind('-') {
+    Some(i) => i,
+    None => return None,
+  };
+  let seq = match cursor[split + 1..].parse() {
+    Ok(seq) => seq,
+    Err(_) => return None,
+  };
+  Some((cursor[..split].to_string(), seq))
+}
+
+fn event_json((id, event, data): Event) -> JsonValue {
+  let mut obj = JsonObject::new();
+  obj.insert("event".to_string(), JsonValue::String(event));
+  obj.insert("data".to_string(), JsonValue::Object(data));
+  if let Some(id) = id {
+    obj.insert("id".to_string(), json!(id));
+  }
+  JsonValue::Object(obj)
+}
+
+fn batch_reply(req: Request, cursor: Option<String>, events: Vec<JsonValue>) -> Reply {
+  let mut data = JsonObject::new();
+  data.insert("cursor".to_string(), cursor.map(JsonValue::String).unwrap_or(JsonValue::Null));
+  data.insert("events".to_string(), JsonValue::Array(events));
+  make_reply(req, data)
+}
+
+// waits for at least one event, then resolves with everything that's queued and the next cursor.
+// resolves early with no events on timeout or shutdown. if the request is dropped before then, the
+// stream goes back into its session.
+struct NextBatch {
+  sessions: Sessions,
+  key: String,
+  events: Option<EventReceiver>,
+  batch: Vec<Event>,
+  timeout: Timeout,
+  shutdown: Box<Future<Item=(), Error=()>>,
+}
+
+impl NextBatch {
+  fn finish(&mut self) -> (Option<String>, Vec<JsonValue>) {
+    let batch: Vec<JsonValue> = self.batch.drain(..).map(event_json).collect();
+    let cursor = self.sessions.finish(&self.key, self.events.take(), &batch);
+    (cursor, batch)
+  }
+}
+
+impl Future for NextBatch {
+  type Item = (Option<String>, Vec<JsonValue>);
+  type Error = Error;
+
+  fn poll(&mut self) -> Poll<Self::Item, Error> {
+    loop {
+      match self.events.as_mut().expect("polled NextBatch after completion").poll() {
+        Ok(Async::Ready(Some(event))) => self.batch.push(event),
+        Ok(Async::NotReady) => break,
+        Ok(Async::Ready(None)) | Err(_) => {
+          self.events = None;
+          return Ok(Async::Ready(self.finish()));
+        },
+      }
+    }
+    let done = !self.batch.is_empty()
+      || self.timeout.poll().map(|a| a.is_ready()).unwrap_or(true)
+      || self.shutdown.poll().map(|a| a.is_ready()).unwrap_or(true);
+    if done {
+      Ok(Async::Ready(self.finish()))
+    } else {
+      Ok(Async::NotReady)
+    }
+  }
+}
+
+impl Drop for NextBatch {
+  fn drop(&mut self) {
+    if self.events.is_some() {
+      // the events already taken off the queue are lost, like they would be for an event stream
+      self.finish();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use {Channel, Method};
+  use memory::MemoryChannel;
+  use futures::future::empty;
+  use tokio_core::reactor::Core;
+
+  fn listen() -> Request {
+    Request::new("/cats".to_string(), Method::Listen, None, JsonObject::new(), JsonObject::new())
+  }
+
+  fn cursor_of(reply: &Reply) -> String {
+    reply.data().unwrap().get("cursor").unwrap().as_str().unwrap().to_string()
+  }
+
+  #[test]
+  fn polls_for_batches_of_events() {
+    let mut core = Core::new().unwrap();
+    let sessions = Sessions::new();
+    let chan = MemoryChannel::new();
+    let timeout = Duration::from_millis(50);
+    let reply = core.run(sessions.start(chan.handle(listen()), timeout, &core.handle())).unwrap();
+    let first = cursor_of(&reply);
+
+    chan.send("created", &JsonObject::new());
+    chan.send("patched", &JsonObject::new());
+    let reply = core.run(sessions.poll(listen(), &first, timeout, Box::new(empty()), &core.handle())).unwrap();
+    assert_eq!(reply.data().unwrap().get("events").unwrap().as_array().unwrap().len(), 2);
+    let second = cursor_of(&reply);
+    assert!(second != first);
+
+    // the previous cursor gets the same batch again, and an idle poll keeps the cursor
+    let reply = core.run(sessions.poll(listen(), &first, timeout, Box::new(empty()), &core.handle())).unwrap();
+    assert_eq!(cursor_of(&reply), second);
+    let reply = core.run(sessions.poll(listen(), &second, timeout, Box::new(empty()), &core.handle())).unwrap();
+    assert_eq!(reply.data().unwrap().get("events").unwrap(), &json!([]));
+    assert_eq!(cursor_of(&reply), second);
+
+    drop(chan);
+    let reply = core.run(sessions.poll(listen(), &second, timeout, Box::new(empty()), &core.handle())).unwrap();
+    assert_eq!(reply.data().unwrap().get("cursor").unwrap(), &JsonValue::Null);
+    assert!(core.run(sessions.poll(listen(), &second, timeout, Box::new(empty()), &core.handle())).is_err());
+  }
+
+  #[test]
+  fn expires_abandoned_sessions() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let sessions = Sessions::new();
+    let chan = MemoryChannel::new();
+    let timeout = Duration::from_millis(20);
+    let abandoned = cursor_of(&core.run(sessions.start(chan.handle(listen()), timeout, &handle)).unwrap());
+    let polled = cursor_of(&core.run(sessions.start(chan.handle(listen()), timeout, &handle)).unwrap());
+    // keeps polling the second session until the first one's deadline has passed, with no other
+    // requests coming in
+    let poll = sessions.poll(listen(), &polled, timeout * 3, Box::new(empty()), &handle);
+    core.run(poll).unwrap();
+    assert_eq!(sessions.sessions.lock().unwrap().len(), 1);
+    core.run(Timeout::new(timeout * 3, &handle).unwrap()).unwrap();
+    assert!(sessions.sessions.lock().unwrap().is_empty());
+    assert!(core.run(sessions.poll(listen(), &abandoned, timeout, Box::new(empty()), &handle)).is_err());
+  }
+}
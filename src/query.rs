@@ -0,0 +1,755 @@
+/*!
+A Feathers-style query language, parsed from request params.
+
+Adapters can use this to support filtering in `list` without each parsing the params themselves.
+`Query::parse` turns params like the ones `queryst` produces from `?age[$gt]=3&name=Fluffy`
+into a `Query`, which can either be checked against objects directly with `Query::matches`, or
+walked to build a query for a real database.
+
+The supported forms are:
+
+- `name=Fluffy`: the field equals the value
+- `age[$gt]=3`: comparison operators `$eq`, `$ne`, `$gt`, `$gte`, `$lt` and `$lte`
+- `color[$in][]=grey&color[$in][]=black`: `$in` and `$nin`, for lists of values
+- `color=grey&color=black`: a list of values without an operator is the same as `$in`
+- `name[$regex]=^Flu.*y$`: a small subset of regular expressions, see `Pattern`
+- `owner[address][city]=Paris` or `owner.address.city=Paris`: nested fields
+- `$or[0][name]=Fluffy&$or[1][age][$lt]=3`: matches if any of the subqueries match
+
+Since query strings only contain strings, values are compared loosely: `"3"` equals the number `3`,
+and `"true"` equals `true`. Other top-level params starting with `$`, like `$sort`, are left for
+the adapter and are ignored here.
+
+Sorting and pagination params are parsed separately by `ListOptions::parse`:
+
+- `$sort=-age,name`: sort by `age` descending, then `name` ascending. `$sort[age]=-1` also works,
+  but since params objects don't keep their order, use the string form to sort by several fields.
+- `$limit=10` and `$skip=20`: return at most 10 items, after skipping the first 20
+- `$cursor=...`: return items after the position in the `next` cursor of a previous page
+
+Results are always sorted by `id` after any `$sort` fields, so the order is deterministic. Adapters
+return pages in the same envelope, `{"data": [...], "total": 42, "limit": 10, "skip": 0, "next":
+"..."}`, built with `Page`.
+*/
+
+use {JsonValue, JsonObject, ErrorKind};
+use std::cmp::Ordering;
+use serde_json;
+use base64;
+
+fn std_error(kind: ErrorKind, err_str: &str) -> (ErrorKind, JsonValue) {
+  let val = json!({
+    "error": {
+      "type": kind.as_string(),
+      "message": err_str.to_string(),
+    }
+  });
+  (kind, val)
+}
+
+/**
+A parsed query, which can be matched against JSON objects.
+*/
+#[derive(Debug, Clone)]
+pub enum Query {
+  /// Matches if all of the subqueries match. An empty `All` matches everything.
+  All(Vec<Query>),
+  /// Matches if any of the subqueries match. An empty `Any` matches nothing.
+  Any(Vec<Query>),
+  /// Matches if the field at the path, like `["owner", "name"]`, satisfies the operator.
+  Field(Vec<String>, Operator),
+}
+
+/**
+A condition on a single field of an object.
+*/
+#[derive(Debug, Clone)]
+pub enum Operator {
+  /// `$eq`, or a plain value
+  Eq(JsonValue),
+  /// `$ne`, also matches if the field is missing
+  Ne(JsonValue),
+  /// `$gt`
+  Gt(JsonValue),
+  /// `$gte`
+  Gte(JsonValue),
+  /// `$lt`
+  Lt(JsonValue),
+  /// `$lte`
+  Lte(JsonValue),
+  /// `$in`, or a plain list of values
+  In(Vec<JsonValue>),
+  /// `$nin`, also matches if the field is missing
+  Nin(Vec<JsonValue>),
+  /// `$regex`, only matches string fields
+  Regex(Pattern),
+}
+
+impl Query {
+  /**
+  Parses request params into a `Query`, returning a `BadRequest` error for unknown operators or
+  malformed values.
+  */
+  pub fn parse(params: &JsonObject) -> Result<Query, (ErrorKind, JsonValue)> {
+    let mut queries = Vec::new();
+    for (key, val) in params {
+      match key.as_str() {
+        "$or" => queries.push(Query::Any(parse_subqueries(val)?)),
+        "$and" => queries.push(Query::All(parse_subqueries(val)?)),
+        _ if key.starts_with("$") => (),
+        _ => {
+          let path = key.split(".").map(|s| s.to_string()).collect();
+          parse_field(path, val, &mut queries)?;
+        },
+      }
+    }
+    Ok(Query::All(queries))
+  }
+
+  /// Returns true if the object satisfies this query.
+  pub fn matches(&self, obj: &JsonObject) -> bool {
+    match self {
+      &Query::All(ref queries) => queries.iter().all(|q| q.matches(obj)),
+      &Query::Any(ref queries) => queries.iter().any(|q| q.matches(obj)),
+      &Query::Field(ref path, ref op) => op.matches(lookup(obj, path)),
+    }
+  }
+}
+
+impl Operator {
+  /// Returns true if the field value, or `None` for a missing field, satisfies this operator.
+  pub fn matches(&self, field: Option<&JsonValue>) -> bool {
+    let field = match (self, field) {
+      (&Operator::Ne(ref val), None) => return val != &JsonValue::Null,
+      (&Operator::Nin(_), None) => return true,
+      (&Operator::Eq(ref val), None) => return val == &JsonValue::Null,
+      (_, None) => return false,
+      (_, Some(f)) => f,
+    };
+    match self {
+      &Operator::Eq(ref val) => loose_eq(field, val),
+      &Operator::Ne(ref val) => !loose_eq(field, val),
+      &Operator::Gt(ref val) => compare(field, val) == Some(Ordering::Greater),
+      &Operator::Gte(ref val) => compare(field, val).map(|o| o != Ordering::Less).unwrap_or(false),
+      &Operator::Lt(ref val) => compare(field, val) == Some(Ordering::Less),
+      &Operator::Lte(ref val) => compare(field, val).map(|o| o != Ordering::Greater).unwrap_or(false),
+      &Operator::In(ref vals) => vals.iter().any(|val| loose_eq(field, val)),
+      &Operator::Nin(ref vals) => !vals.iter().any(|val| loose_eq(field, val)),
+      &Operator::Regex(ref pattern) => match field {
+        &JsonValue::String(ref s) => pattern.matches(s),
+        _ => false,
+      },
+    }
+  }
+}
+
+fn parse_subqueries(val: &JsonValue) -> Result<Vec<Query>, (ErrorKind, JsonValue)> {
+  let items: Vec<&JsonValue> = match val {
+    &JsonValue::Array(ref arr) => arr.iter().collect(),
+    &JsonValue::Object(ref obj) => obj.values().collect(),
+    _ => return Err(std_error(ErrorKind::BadRequest, "$or and $and need a list of queries")),
+  };
+  items.into_iter().map(|item| match item {
+    &JsonValue::Object(ref obj) => Query::parse(obj),
+    _ => Err(std_error(ErrorKind::BadRequest, "$or and $and need a list of queries")),
+  }).collect()
+}
+
+fn parse_field(path: Vec<String>, val: &JsonValue, queries: &mut Vec<Query>) -> Result<(), (ErrorKind, JsonValue)> {
+  match val {
+    &JsonValue::Object(ref obj) => {
+      for (key, val) in obj {
+        if key.starts_with("$") {
+          queries.push(Query::Field(path.clone(), parse_operator(key, val)?));
+        } else {
+          let mut path = path.clone();
+          path.extend(key.split(".").map(|s| s.to_string()));
+          parse_field(path, val, queries)?;
+        }
+      }
+    },
+    &JsonValue::Array(ref arr) => queries.push(Query::Field(path, Operator::In(arr.clone()))),
+    _ => queries.push(Query::Field(path, Operator::Eq(val.clone()))),
+  }
+  Ok(())
+}
+
+fn parse_operator(key: &str, val: &JsonValue) -> Result<Operator, (ErrorKind, JsonValue)> {
+  let list = || match val {
+    &JsonValue::Array(ref arr) => arr.clone(),
+    &JsonValue::Object(ref obj) => obj.values().cloned().collect(),
+    _ => vec![val.clone()],
+  };
+  Ok(match key {
+    "$eq" => Operator::Eq(val.clone()),
+    "$ne" => Operator::Ne(val.clone()),
+    "$gt" => Operator::Gt(val.clone()),
+    "$gte" => Operator::Gte(val.clone()),
+    "$lt" => Operator::Lt(val.clone()),
+    "$lte" => Operator::Lte(val.clone()),
+    "$in" => Operator::In(list()),
+    "$nin" => Operator::Nin(list()),
+    "$regex" => match val {
+      &JsonValue::String(ref s) => Operator::Regex(Pattern::new(s)?),
+      _ => return Err(std_error(ErrorKind::BadRequest, "$regex needs a string")),
+    },
+    _ => return Err(std_error(ErrorKind::BadRequest, &format!("unknown query operator {}", key))),
+  })
+}
+
+/// Finds the value at `path` inside `obj`, if there is one.
+pub fn lookup<'a>(obj: &'a JsonObject, path: &[String]) -> Option<&'a JsonValue> {
+  let (first, rest) = match path.split_first() {
+    Some(t) => t,
+    None => return None,
+  };
+  let mut val = obj.get(first);
+  for key in rest {
+    val = match val {
+      Some(&JsonValue::Object(ref o)) => o.get(key),
+      _ => None,
+    };
+  }
+  val
+}
+
+fn as_number(val: &JsonValue) -> Option<f64> {
+  match val {
+    &JsonValue::Number(ref n) => n.as_f64(),
+    &JsonValue::String(ref s) => s.parse().ok(),
+    _ => None,
+  }
+}
+
+/**
+Compares two JSON values, converting strings to numbers if the other value is a number. Returns
+`None` if the values can't be compared, like an object and a string.
+*/
+pub fn compare(a: &JsonValue, b: &JsonValue) -> Option<Ordering> {
+  match (a, b) {
+    (&JsonValue::String(ref a), &JsonValue::String(ref b)) => Some(a.cmp(b)),
+    (&JsonValue::Number(_), _) | (_, &JsonValue::Number(_)) => {
+      match (as_number(a), as_number(b)) {
+        (Some(a), Some(b)) => a.partial_cmp(&b),
+        _ => None,
+      }
+    },
+    (&JsonValue::Bool(a), &JsonValue::Bool(b)) => Some(a.cmp(&b)),
+    _ => None,
+  }
+}
+
+fn loose_eq(field: &JsonValue, val: &JsonValue) -> bool {
+  if field == val {
+    return true;
+  }
+  match (field, val) {
+    (&JsonValue::Bool(b), &JsonValue::String(ref s)) => s == if b { "true" } else { "false" },
+    (&JsonValue::Null, &JsonValue::String(ref s)) => s == "null",
+    _ => compare(field, val) == Some(Ordering::Equal),
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Quantifier {
+  One,
+  Optional,
+  Star,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+  // `None` is `.`, which matches any character
+  ch: Option<char>,
+  quantifier: Quantifier,
+}
+
+impl Token {
+  fn matches(&self, c: char) -> bool {
+    self.ch.map(|ch| ch == c).unwrap_or(true)
+  }
+}
+
+/**
+A "regex-lite" pattern used by `$regex`.
+
+Supports literal characters, `.` for any character, the `?`, `*` and `+` quantifiers, the `^` and
+`$` anchors, and `\` to escape any of those. Without anchors, the pattern can match anywhere in the
+string.
+*/
+#[derive(Debug, Clone)]
+pub struct Pattern {
+  tokens: Vec<Token>,
+  anchor_start: bool,
+  anchor_end: bool,
+}
+
+impl Pattern {
+  pub fn new(pattern: &str) -> Result<Pattern, (ErrorKind, JsonValue)> {
+    let mut chars = pattern.chars().peekable();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut anchor_start = false;
+    let mut anchor_end = false;
+    if chars.peek() == Some(&'^') {
+      chars.next();
+      anchor_start = true;
+    }
+    while let Some(c) = chars.next() {
+      let ch = match c {
+        '\\' => match chars.next() {
+          Some(escaped) => Some(escaped),
+          None => return Err(std_error(ErrorKind::BadRequest, "$regex can't end with \\")),
+        },
+        '$' if chars.peek().is_none() => {
+          anchor_end = true;
+          break;
+        },
+        '.' => None,
+        '?' | '*' | '+' => {
+          let last = match tokens.last_mut() {
+            Some(t) if t.quantifier == Quantifier::One => t,
+            _ => return Err(std_error(ErrorKind::BadRequest, "$regex has a misplaced quantifier")),
+          };
+          // `x+` is the same as `xx*`
+          let repeated = match c {
+            '?' => {
+              last.quantifier = Quantifier::Optional;
+              None
+            },
+            '*' => {
+              last.quantifier = Quantifier::Star;
+              None
+            },
+            _ => Some(Token { ch: last.ch, quantifier: Quantifier::Star }),
+          };
+          tokens.extend(repeated);
+          continue;
+        },
+        c => Some(c),
+      };
+      tokens.push(Token { ch: ch, quantifier: Quantifier::One });
+    }
+    Ok(Pattern {
+      tokens: tokens,
+      anchor_start: anchor_start,
+      anchor_end: anchor_end,
+    })
+  }
+
+  /**
+  Returns true if the pattern matches the string. This keeps track of every token the pattern could
+  be at while it reads the string once, instead of backtracking, so it takes at most the length of
+  the string times the length of the pattern, however the pattern is written.
+  */
+  pub fn matches(&self, s: &str) -> bool {
+    let end = self.tokens.len();
+    let mut states = vec![false; end + 1];
+    self.enter(&mut states, 0);
+    for c in s.chars() {
+      if states[end] && !self.anchor_end {
+        return true;
+      }
+      let mut next = vec![false; end + 1];
+      for (i, token) in self.tokens.iter().enumerate() {
+        if states[i] && token.matches(c) {
+          // `*` can match again, everything else moves on to the next token
+          let to = if token.quantifier == Quantifier::Star { i } else { i + 1 };
+          self.enter(&mut next, to);
+        }
+      }
+      // without `^`, a match can start anywhere
+      if !self.anchor_start {
+        self.enter(&mut next, 0);
+      }
+      states = next;
+    }
+    states[end]
+  }
+
+  // adds a state, and the states after it that can be reached by matching nothing
+  fn enter(&self, states: &mut [bool], mut i: usize) {
+    while !states[i] {
+      states[i] = true;
+      match self.tokens.get(i) {
+        Some(t) if t.quantifier != Quantifier::One => i += 1,
+        _ => return,
+      }
+    }
+  }
+}
+
+/// The direction to sort a field in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SortOrder {
+  Ascending,
+  Descending,
+}
+
+/**
+Sorting and pagination options for `list` requests, parsed from `$sort`, `$limit`, `$skip` and
+`$cursor` params.
+*/
+#[derive(Debug, Clone)]
+pub struct ListOptions {
+  /// Fields to sort by, in order of precedence. The `id_field` is always used as a final tiebreaker.
+  pub sort: Vec<(Vec<String>, SortOrder)>,
+  pub limit: Option<usize>,
+  pub skip: usize,
+  /// The sort values of the last item on the previous page, decoded from `$cursor`.
+  pub cursor: Option<Vec<JsonValue>>,
+  /// The field holding each item's id, `"id"` unless the adapter changes it.
+  pub id_field: String,
+}
+
+/**
+A page of results from a `list` request, which adapters can turn into a response with
+`to_object`.
+*/
+#[derive(Debug, Clone)]
+pub struct Page {
+  pub data: Vec<JsonObject>,
+  /// The number of items matching the query, ignoring `$limit`, `$skip` and `$cursor`.
+  pub total: usize,
+  pub limit: Option<usize>,
+  pub skip: usize,
+  /// A cursor for the next page, if there are more items.
+  pub next: Option<String>,
+}
+
+impl Page {
+  /// Creates the response envelope, like `{"data": [...], "total": 42, ...}`.
+  pub fn to_object(self) -> JsonObject {
+    let mut obj = JsonObject::new();
+    obj.insert("total".to_string(), json!(self.total));
+    obj.insert("limit".to_string(), json!(self.limit));
+    obj.insert("skip".to_string(), json!(self.skip));
+    obj.insert("next".to_string(), json!(self.next));
+    obj.insert("data".to_string(), JsonValue::Array(self.data.into_iter().map(JsonValue::Object).collect()));
+    obj
+  }
+}
+
+fn parse_count(params: &JsonObject, key: &str) -> Result<Option<usize>, (ErrorKind, JsonValue)> {
+  match params.get(key) {
+    None => Ok(None),
+    Some(&JsonValue::String(ref s)) => s.parse().map(Some).map_err(|_| std_error(ErrorKind::BadRequest, &format!("{} must be a positive integer", key))),
+    Some(&JsonValue::Number(ref n)) if n.is_u64() => Ok(n.as_u64().map(|n| n as usize)),
+    _ => Err(std_error(ErrorKind::BadRequest, &format!("{} must be a positive integer", key))),
+  }
+}
+
+fn parse_sort_order(val: &JsonValue) -> Result<SortOrder, (ErrorKind, JsonValue)> {
+  match as_number(val) {
+    Some(n) if n > 0.0 => Ok(SortOrder::Ascending),
+    Some(n) if n < 0.0 => Ok(SortOrder::Descending),
+    _ => Err(std_error(ErrorKind::BadRequest, "$sort directions must be 1 or -1")),
+  }
+}
+
+impl ListOptions {
+  /// Parses the sorting and pagination params, returning a `BadRequest` error for invalid values.
+  pub fn parse(params: &JsonObject) -> Result<ListOptions, (ErrorKind, JsonValue)> {
+    let path = |s: &str| s.split(".").map(|s| s.to_string()).collect::<Vec<String>>();
+    let sort = match params.get("$sort") {
+      None => Vec::new(),
+      Some(&JsonValue::String(ref s)) => s.split(",").filter(|f| f != &"").map(|field| {
+        if field.starts_with("-") {
+          (path(&field[1..]), SortOrder::Descending)
+        } else {
+          (path(field.trim_start_matches("+")), SortOrder::Ascending)
+        }
+      }).collect(),
+      Some(&JsonValue::Object(ref obj)) => {
+        let mut sort = Vec::new();
+        for (field, dir) in obj {
+          sort.push((path(field), parse_sort_order(dir)?));
+        }
+        sort
+      },
+      _ => return Err(std_error(ErrorKind::BadRequest, "$sort must be a list of fields")),
+    };
+    let cursor = match params.get("$cursor") {
+      None => None,
+      Some(&JsonValue::String(ref s)) => {
+        let bad_cursor = || std_error(ErrorKind::BadRequest, "invalid $cursor");
+        let decoded = base64::decode_config(s, base64::URL_SAFE).map_err(|_| bad_cursor())?;
+        match serde_json::from_slice(&decoded) {
+          Ok(JsonValue::Array(vals)) => if vals.len() == sort.len() + 1 {
+            Some(vals)
+          } else {
+            return Err(bad_cursor());
+          },
+          _ => return Err(bad_cursor()),
+        }
+      },
+      _ => return Err(std_error(ErrorKind::BadRequest, "invalid $cursor")),
+    };
+    Ok(ListOptions {
+      sort: sort,
+      limit: parse_count(params, "$limit")?,
+      skip: parse_count(params, "$skip")?.unwrap_or(0),
+      cursor: cursor,
+      id_field: "id".to_string(),
+    })
+  }
+
+  // the values an item is sorted by, with the id last
+  fn sort_values(&self, item: &JsonObject) -> Vec<JsonValue> {
+    let id_path = vec![self.id_field.clone()];
+    self.sort
+      .iter()
+      .map(|&(ref path, _)| path)
+      .chain(Some(&id_path))
+      .map(|path| lookup(item, path).cloned().unwrap_or(JsonValue::Null))
+      .collect()
+  }
+
+  fn compare_values(&self, a: &[JsonValue], b: &[JsonValue]) -> Ordering {
+    let orders = self.sort.iter().map(|&(_, ref order)| order).chain(Some(&SortOrder::Ascending));
+    for ((a, b), order) in a.iter().zip(b.iter()).zip(orders) {
+      let ord = sort_compare(a, b);
+      let ord = if order == &SortOrder::Descending { ord.reverse() } else { ord };
+      if ord != Ordering::Equal {
+        return ord;
+      }
+    }
+    Ordering::Equal
+  }
+
+  /// Sorts the items by the `$sort` fields, then by `id`.
+  pub fn sort(&self, items: &mut Vec<JsonObject>) {
+    items.sort_by(|a, b| self.compare_values(&self.sort_values(a), &self.sort_values(b)));
+  }
+
+  /// Returns the cursor pointing just after this item.
+  pub fn cursor_for(&self, item: &JsonObject) -> String {
+    let vals = JsonValue::Array(self.sort_values(item));
+    base64::encode_config(vals.to_string().as_bytes(), base64::URL_SAFE)
+  }
+
+  /**
+  Sorts and paginates all the items matching a query. This is meant for adapters that load the
+  items into memory; database adapters will want to build a `Page` themselves.
+  */
+  pub fn paginate(&self, mut items: Vec<JsonObject>) -> Page {
+    let total = items.len();
+    self.sort(&mut items);
+    let after_cursor: Vec<JsonObject> = match self.cursor {
+      Some(ref cursor) => items
+        .into_iter()
+        .filter(|item| self.compare_values(&self.sort_values(item), cursor) == Ordering::Greater)
+        .collect(),
+      None => items,
+    };
+    let remaining = after_cursor.len().saturating_sub(self.skip);
+    let data: Vec<JsonObject> = after_cursor
+      .into_iter()
+      .skip(self.skip)
+      .take(self.limit.unwrap_or(remaining))
+      .collect();
+    let next = if data.len() < remaining {
+      data.last().map(|item| self.cursor_for(item))
+    } else {
+      None
+    };
+    Page {
+      data: data,
+      total: total,
+      limit: self.limit,
+      skip: self.skip,
+      next: next,
+    }
+  }
+}
+
+fn type_rank(val: &JsonValue) -> u8 {
+  match val {
+    &JsonValue::Null => 0,
+    &JsonValue::Bool(_) => 1,
+    &JsonValue::Number(_) => 2,
+    &JsonValue::String(_) => 3,
+    &JsonValue::Array(_) => 4,
+    &JsonValue::Object(_) => 5,
+  }
+}
+
+// the number a string looks like, leaving out ones like `"nan"` and `"inf"` that don't sort
+fn numeric(s: &str) -> Option<f64> {
+  s.parse::<f64>().ok().and_then(|n| if n.is_finite() { Some(n) } else { None })
+}
+
+/**
+A total ordering on JSON values for sorting: `null` first, then booleans, numbers, strings, arrays
+and objects. Strings that look like numbers, like the ids `"9"` and `"10"`, sort numerically, and
+before all other strings.
+*/
+pub fn sort_compare(a: &JsonValue, b: &JsonValue) -> Ordering {
+  match (a, b) {
+    (&JsonValue::String(ref x), &JsonValue::String(ref y)) => {
+      match (numeric(x), numeric(y)) {
+        // equal numbers like `"1"` and `"1.0"` still need an order
+        (Some(nx), Some(ny)) => nx.partial_cmp(&ny).unwrap_or(Ordering::Equal).then_with(|| x.cmp(y)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => x.cmp(y),
+      }
+    },
+    (&JsonValue::Array(ref x), &JsonValue::Array(ref y)) => {
+      for (x, y) in x.iter().zip(y.iter()) {
+        let ord = sort_compare(x, y);
+        if ord != Ordering::Equal {
+          return ord;
+        }
+      }
+      x.len().cmp(&y.len())
+    },
+    (&JsonValue::Object(_), &JsonValue::Object(_)) => a.to_string().cmp(&b.to_string()),
+    _ => match type_rank(a).cmp(&type_rank(b)) {
+      Ordering::Equal => compare(a, b).unwrap_or(Ordering::Equal),
+      ord => ord,
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use obj;
+  use queryst::parse as query_parse;
+
+  fn query(s: &str) -> Query {
+    match query_parse(s).unwrap() {
+      JsonValue::Object(params) => Query::parse(&params).unwrap(),
+      _ => Query::All(Vec::new()),
+    }
+  }
+
+  #[test]
+  fn matches_comparisons() {
+    let cat = obj(json!({"name": "Fluffy", "age": 3, "indoor": true}));
+    assert!(query("").matches(&cat));
+    assert!(query("age=3&indoor=true").matches(&cat));
+    assert!(query("age[$gt]=2&age[$lte]=3").matches(&cat));
+    assert!(!query("age[$gt]=3").matches(&cat));
+    assert!(query("name[$ne]=Tom&owner[$ne]=Sam").matches(&cat));
+    assert!(!query("owner[$gt]=0").matches(&cat));
+  }
+
+  #[test]
+  fn matches_lists() {
+    let cat = obj(json!({"color": "grey"}));
+    assert!(query("color[$in][]=grey&color[$in][]=black").matches(&cat));
+    assert!(query("color=grey&color=black").matches(&cat));
+    assert!(!query("color[$nin][]=grey").matches(&cat));
+    assert!(query("color[$nin]=white&missing[$nin]=white").matches(&cat));
+  }
+
+  #[test]
+  fn matches_or_and_nested_fields() {
+    let cat = obj(json!({"name": "Fluffy", "owner": {"address": {"city": "Paris"}}}));
+    assert!(query("owner[address][city]=Paris").matches(&cat));
+    assert!(query("owner.address.city=Paris").matches(&cat));
+    assert!(!query("owner.address.city=Rome").matches(&cat));
+    assert!(query("$or[0][name]=Tom&$or[1][owner][address][city]=Paris").matches(&cat));
+    assert!(!query("$or[0][name]=Tom&$or[1][name]=Sam").matches(&cat));
+  }
+
+  #[test]
+  fn matches_regexes() {
+    assert!(Pattern::new("^Flu.*y$").unwrap().matches("Fluffy"));
+    assert!(Pattern::new("uf+").unwrap().matches("Fluffy"));
+    assert!(Pattern::new("^Fluf?fy").unwrap().matches("Flufy"));
+    assert!(!Pattern::new("^luf").unwrap().matches("Fluffy"));
+    assert!(!Pattern::new("ff$").unwrap().matches("Fluffy"));
+    assert!(Pattern::new("a\\.b").unwrap().matches("a.b"));
+    assert!(!Pattern::new("a\\.b").unwrap().matches("axb"));
+    assert!(Pattern::new("*a").is_err());
+    assert!(Pattern::new("^a+b?c*$").unwrap().matches("aaac"));
+    assert!(!Pattern::new("^a+b?c*$").unwrap().matches("bc"));
+    assert!(Pattern::new("").unwrap().matches(""));
+    assert!(Pattern::new("^$").unwrap().matches(""));
+    assert!(!Pattern::new("^$").unwrap().matches("a"));
+  }
+
+  #[test]
+  fn matches_pathological_regexes_quickly() {
+    // this took exponential time with backtracking
+    let text: String = ::std::iter::repeat('a').take(5000).collect();
+    let pattern = Pattern::new(".*.*.*.*.*.*.*.*.*.*.*.*x").unwrap();
+    assert!(!pattern.matches(&text));
+    assert!(!Pattern::new("^a*a*a*a*a*a*a*a*a*a*a*a*$").unwrap().matches(&format!("{}b", text)));
+    assert!(pattern.matches(&format!("{}x", text)));
+  }
+
+  #[test]
+  fn rejects_unknown_operators() {
+    let params = obj(json!({"age": {"$near": 3}}));
+    assert!(Query::parse(&params).is_err());
+  }
+
+  fn ids(page: &Page) -> Vec<String> {
+    page.data.iter().map(|item| item.get("id").unwrap().as_str().unwrap().to_string()).collect()
+  }
+
+  fn cats() -> Vec<JsonObject> {
+    vec![
+      obj(json!({"id": "10", "name": "Tom", "age": 3})),
+      obj(json!({"id": "2", "name": "Fluffy", "age": 5})),
+      obj(json!({"id": "9", "name": "Sam", "age": 3})),
+      obj(json!({"id": "4", "name": "Max"})),
+    ]
+  }
+
+  fn options(s: &str) -> ListOptions {
+    match query_parse(s).unwrap() {
+      JsonValue::Object(params) => ListOptions::parse(&params).unwrap(),
+      _ => ListOptions::parse(&JsonObject::new()).unwrap(),
+    }
+  }
+
+  #[test]
+  fn sorts_deterministically() {
+    assert_eq!(ids(&options("").paginate(cats())), vec!["2", "4", "9", "10"]);
+    assert_eq!(ids(&options("$sort=-age,name").paginate(cats())), vec!["2", "9", "10", "4"]);
+    assert_eq!(ids(&options("$sort[age]=1").paginate(cats())), vec!["4", "9", "10", "2"]);
+  }
+
+  #[test]
+  fn sorts_mixed_strings_totally() {
+    let mut vals: Vec<JsonValue> = vec!["1a", "nan", "10", "b", "9", "1.0", "inf", "1", "-2"].into_iter().map(|s| json!(s)).collect();
+    vals.sort_by(sort_compare);
+    assert_eq!(vals, vec![json!("-2"), json!("1"), json!("1.0"), json!("9"), json!("10"), json!("1a"), json!("b"), json!("inf"), json!("nan")]);
+    // every pair agrees with the sorted order, so the order is transitive
+    for (i, a) in vals.iter().enumerate() {
+      for (j, b) in vals.iter().enumerate() {
+        assert_eq!(sort_compare(a, b), i.cmp(&j));
+      }
+    }
+  }
+
+  #[test]
+  fn paginates_with_skip_and_limit() {
+    let page = options("$sort=name&$limit=2&$skip=1").paginate(cats());
+    assert_eq!(ids(&page), vec!["4", "9"]);
+    assert_eq!(page.total, 4);
+    assert!(page.next.is_some());
+    let page = options("$limit=2&$skip=2").paginate(cats());
+    assert_eq!(ids(&page), vec!["9", "10"]);
+    assert!(page.next.is_none());
+  }
+
+  #[test]
+  fn paginates_with_cursors() {
+    let mut seen = Vec::new();
+    let mut query = "$sort=-age&$limit=3".to_string();
+    loop {
+      let page = options(&query).paginate(cats());
+      seen.extend(ids(&page));
+      match page.next {
+        Some(next) => query = format!("$sort=-age&$limit=3&$cursor={}", next),
+        None => break,
+      }
+    }
+    assert_eq!(seen, vec!["2", "9", "10", "4"]);
+  }
+}
@@ -8,10 +8,12 @@ use hyper::Chunk as HyperChunk;
 use futures::{Poll, Stream, Async, IntoFuture};
 use futures::future::{ok, FutureResult, BoxFuture, Future};
 use futures::stream::BoxStream;
-use futures::sync::mpsc;
+use channel::{Overflow, Event, StreamOptions};
 use Sender;
 
 type ChunkReceiver = BoxStream<HyperChunk, ()>;
+// only used internally
+pub type EventReceiver = BoxStream<Event, ()>;
 
 /**
 A successful response with JSON data to be sent back to the client.
@@ -38,14 +40,14 @@ pub struct Reply {
 
 enum ReplyData {
   Value(JsonObject),
-  Stream(ChunkReceiver),
+  Stream(EventReceiver, StreamOptions),
 }
 
 impl fmt::Debug for ReplyData {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     match self {
       &ReplyData::Value(ref val) => write!(f, "ReplyData::Value({:?})", val),
-      &ReplyData::Stream(_) => write!(f, "ReplyData::Stream(<stream>)"),
+      &ReplyData::Stream(_, _) => write!(f, "ReplyData::Stream(<stream>)"),
     }
   }
 }
@@ -59,21 +61,62 @@ pub fn make_reply(req: Request, data: JsonObject) -> Reply {
 }
 
 // only used internally
-pub fn make_streamed_reply(req: Request) -> (Sender, Reply) {
-  let (tx, rx) = mpsc::unbounded();
-  let rx = rx
-    .map(|val: (String, JsonObject)| -> HyperChunk {
-      format!("event:{}\ndata:{}\n\n", val.0, JsonValue::Object(val.1)).into()
-    })
-    .boxed();
+pub fn make_streamed_reply(req: Request, limit: usize, overflow: Overflow, options: StreamOptions) -> (Sender, Reply) {
+  let (sender, rx) = channel::new_queue(limit, overflow);
   let reply = Reply {
     req: req,
-    data: ReplyData::Stream(rx)
+    data: ReplyData::Stream(rx.boxed(), options)
   };
-  let sender = channel::new_sender(tx);
   (sender, reply)
 }
 
+// only used internally, the channel's settings if this is a streaming reply
+pub fn stream_options(reply: &Reply) -> Option<StreamOptions> {
+  match reply.data {
+    ReplyData::Stream(_, options) => Some(options),
+    _ => None,
+  }
+}
+
+// only used internally, gives back the reply if it isn't a streaming reply
+pub fn take_event_stream(reply: Reply) -> Result<EventReceiver, Reply> {
+  match reply.data {
+    ReplyData::Stream(stream, _) => Ok(stream),
+    data => Err(Reply {
+      req: reply.req,
+      data: data,
+    }),
+  }
+}
+
+// only used internally, returns the response without a body, and the body separately
+pub fn to_http_parts<B>(reply: Reply) -> (http::Response<B>, Body) {
+  let resp = http::Response::new();
+
+  match reply.data {
+    ReplyData::Value(val) => {
+      let resp_str = JsonValue::Object(val).to_string();
+      let resp = resp
+        .with_header(ContentLength(resp_str.len() as u64))
+        .with_header(ContentType(mime::APPLICATION_JSON));
+      (resp, Body::Once(Some(resp_str.into())))
+    },
+    ReplyData::Stream(stream, _) => {
+      let stream = stream
+        .map(|(id, event, data): Event| -> HyperChunk {
+          match id {
+            Some(id) => format!("id:{}\nevent:{}\ndata:{}\n\n", id, event, JsonValue::Object(data)),
+            None => format!("event:{}\ndata:{}\n\n", event, JsonValue::Object(data)),
+          }.into()
+        })
+        .boxed();
+      let resp = resp
+        .with_header(ContentType(mime::TEXT_EVENT_STREAM));
+      (resp, Body::Stream(stream))
+    },
+  }
+}
+
 impl Reply {
   pub fn data(&self) -> Option<&JsonObject> {
     match self.data {
@@ -92,22 +135,8 @@ impl Reply {
   // TODO data_then accepts a function that returns a future<JsonObject, Error>
 
   pub fn to_http(self) -> http::Response<Body> {
-    let resp = http::Response::new();
-
-    match self.data {
-      ReplyData::Value(val) => {
-        let resp_str = JsonValue::Object(val).to_string();
-        resp
-          .with_header(ContentLength(resp_str.len() as u64))
-          .with_header(ContentType(mime::APPLICATION_JSON))
-          .with_body(Body::Once(Some(resp_str.into())))
-      },
-      ReplyData::Stream(stream) => {
-        resp
-          .with_header(ContentType(mime::TEXT_EVENT_STREAM))
-          .with_body(Body::Stream(stream))
-      },
-    }
+    let (resp, body) = to_http_parts(self);
+    resp.with_body(body)
   }
 
   pub fn method(&self) -> Method {
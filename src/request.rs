@@ -1,6 +1,9 @@
 use super::{JsonObject, JsonValue, Reply, Error};
 use reply::make_reply;
+use typed;
+use serde::{Deserialize, Serialize};
 use futures::future::{IntoFuture, ok, FutureResult, AndThen, Future, BoxFuture};
+use std::net::SocketAddr;
 
 /**
 A type of request, for instance "List" or "Post".
@@ -9,12 +12,18 @@ These mostly correspond to the HTTP methods, with the addition of `List`, `Liste
 
 - `List` is a `GET` request with an ID on a resource, such as `GET /cats`.
 - `Listen` is a `GET`
-request with a `Accept: text/event-stream` header. `Listen` requests may or may not have IDs, so
-both `GET /cats` and `GET /cats/123` with the `event-stream` header would be a `Listen` request.
-- `Action` is a custom action on a specific resource ID. For instance, `POST /cats/123/feed` would
-be `Action("feed")`.
+request with a `Accept: text/event-stream` header, or a `GET` request upgrading to a WebSocket.
+`GET /cats?poll=<cursor>` long-polling requests are also `Listen` requests.
+`Listen` requests may or may not have IDs, so both `GET /cats` and `GET /cats/123` with the
+`event-stream` header would be a `Listen` request.
+- `Action` is a custom action on a specific resource ID, or on the whole collection. For instance,
+`POST /cats/123/feed` would be `Action("feed")` with the ID `123`, and `POST /cats/bulk-import`
+would be `Action("bulk-import")` with no ID. The request body is the action's data. Actions on the
+whole collection, and read-only actions reached with `GET`, have to be declared by the handler;
+see `Handler::actions`.
 
-Note that we don't support `PUT` requests currently, for simplicity.
+`Put` replaces the object at an ID with the request data, creating it if it doesn't exist yet,
+so sending the same `PUT` twice has the same effect as sending it once.
 */
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Method {
@@ -28,9 +37,12 @@ pub enum Method {
   Post,
   /// `PATCH /resource/123`
   Patch,
+  /// `PUT /resource/123`, idempotent
+  Put,
   /// Either `GET /resource/` or `GET /resource/123`, with the `Accept: text/event-stream` header
+  /// or a WebSocket upgrade, or a long-polling `?poll=` query
   Listen,
-  /// `POST /resource/123/actionname`
+  /// `POST /resource/123/actionname` or `POST /resource/actionname`, or `GET` for read-only actions
   Action(String),
 }
 
@@ -42,6 +54,7 @@ impl Method {
       &Method::Delete => "delete",
       &Method::Post => "post",
       &Method::Patch => "patch",
+      &Method::Put => "put",
       &Method::Listen => "listen",
       &Method::Action(ref action) => action,
     }.to_string()
@@ -58,6 +71,11 @@ pub struct Request {
   data: JsonObject,
   resource: String,
   method: Method,
+  last_event_id: Option<String>,
+  headers: JsonObject,
+  cookies: JsonObject,
+  remote_addr: Option<SocketAddr>,
+  json_patch: Option<Vec<JsonValue>>,
   null: JsonValue,
 }
 
@@ -69,6 +87,11 @@ impl Request {
       id: id,
       data: data,
       params: params,
+      last_event_id: None,
+      headers: JsonObject::new(),
+      cookies: JsonObject::new(),
+      remote_addr: None,
+      json_patch: None,
       null: JsonValue::Null,
     }
   }
@@ -77,6 +100,14 @@ impl Request {
     make_reply(self, reply)
   }
 
+  /**
+  Like `into_reply`, but with any data that serializes to a JSON object, like a struct deriving
+  `Serialize`. Returns a `ServerError` if it serializes to something else.
+  */
+  pub fn into_reply_from<T: Serialize>(self, reply: T) -> Result<Reply, Error> {
+    typed::to_object(reply).map(|data| make_reply(self, data))
+  }
+
   // TODO data_then accepts a function that returns a future<JsonValue, Error>
 
   pub fn method(&self) -> Method {
@@ -107,6 +138,87 @@ impl Request {
     self.params.insert(key, val);
   }
 
+  /**
+  Deserializes the params into `T`, like a struct deriving `Deserialize`. Since params from the
+  query string are always strings, strings like `"5"` and `"true"` are accepted for numbers and
+  bools. Returns a `BadRequest` error saying which field didn't fit if they can't be deserialized.
+  */
+  pub fn params_as<T: Deserialize>(&self) -> Result<T, Error> {
+    typed::from_object(&self.params, true)
+  }
+
+  /// For a `Listen` request from a reconnecting event-stream client, the id of the last event it got.
+  pub fn last_event_id(&self) -> Option<&str> {
+    self.last_event_id.as_ref().map(|s| s.as_str())
+  }
+
+  pub fn set_last_event_id(&mut self, id: Option<String>) {
+    self.last_event_id = id;
+  }
+
+  /**
+  The HTTP headers the request was sent with, keyed by lowercased name, like `"authorization"`.
+  Headers sent more than once are joined with `", "`. Requests sent over a WebSocket get the headers
+  of the request that opened it.
+  */
+  pub fn headers(&self) -> &JsonObject {
+    &self.headers
+  }
+
+  pub fn headers_mut(&mut self) -> &mut JsonObject {
+    &mut self.headers
+  }
+
+  /// Looks up a header by name, ignoring case. Returns `JsonValue::Null` if it wasn't sent.
+  pub fn header(&self, name: &str) -> &JsonValue {
+    self.headers.get(&name.to_lowercase()).unwrap_or(&self.null)
+  }
+
+  pub fn set_header(&mut self, name: &str, val: JsonValue) {
+    self.headers.insert(name.to_lowercase(), val);
+  }
+
+  /// The cookies from the request's `Cookie` header, keyed by name.
+  pub fn cookies(&self) -> &JsonObject {
+    &self.cookies
+  }
+
+  pub fn cookies_mut(&mut self) -> &mut JsonObject {
+    &mut self.cookies
+  }
+
+  pub fn cookie(&self, name: &str) -> &JsonValue {
+    self.cookies.get(name).unwrap_or(&self.null)
+  }
+
+  pub fn set_cookie(&mut self, name: String, val: JsonValue) {
+    self.cookies.insert(name, val);
+  }
+
+  /**
+  The address of the client's socket, if the request came over the network. Behind a proxy, this is
+  the proxy's address, and the client's is usually in the `X-Forwarded-For` header.
+  */
+  pub fn remote_addr(&self) -> Option<SocketAddr> {
+    self.remote_addr
+  }
+
+  pub fn set_remote_addr(&mut self, addr: Option<SocketAddr>) {
+    self.remote_addr = addr;
+  }
+
+  /**
+  For a `Patch` request sent with `Content-Type: application/json-patch+json`, the JSON Patch
+  operations to apply, in which case the request data is empty. See the `patch` module.
+  */
+  pub fn json_patch(&self) -> Option<&[JsonValue]> {
+    self.json_patch.as_ref().map(|ops| &ops[..])
+  }
+
+  pub fn set_json_patch(&mut self, ops: Option<Vec<JsonValue>>) {
+    self.json_patch = ops;
+  }
+
   pub fn data(&self) -> &JsonObject {
     &self.data
   }
@@ -115,6 +227,18 @@ impl Request {
     &mut self.data
   }
 
+  /**
+  Deserializes the request data into `T`, like a struct deriving `Deserialize`. Returns a
+  `BadRequest` error saying which field didn't fit if it can't be deserialized, with JSON like:
+
+  ```json
+  {"error": {"type": "bad_request", "message": "invalid type: string \"old\", expected u32 at `owner.age`", "field": "owner.age"}}
+  ```
+  */
+  pub fn data_as<T: Deserialize>(&self) -> Result<T, Error> {
+    typed::from_object(&self.data, false)
+  }
+
   pub fn boxed(self) -> BoxFuture<Request, Error> {
     ok(self).boxed()
   }
@@ -0,0 +1,269 @@
+use {Request, Reply, Error, Method, Adapter, Channel, Handler, Action};
+use util::publish_reply_with_id_field;
+use futures::{BoxFuture, Future, IntoFuture};
+use futures::future::ok;
+use std::sync::Arc;
+
+type BeforeHook = Arc<Fn(Request) -> BoxFuture<Request, Error> + Send + Sync>;
+type AfterHook = Arc<Fn(Reply) -> BoxFuture<Reply, Error> + Send + Sync>;
+type ErrorHook = Arc<Fn(Error) -> Error + Send + Sync>;
+type ActionHandler = Arc<Fn(Request) -> BoxFuture<Reply, Error> + Send + Sync>;
+
+// a hook, and the methods it runs on. no methods means it runs on every method.
+struct Hook<H> {
+  methods: Vec<Method>,
+  hook: H,
+}
+
+fn hooks_for<H: Clone>(hooks: &[Hook<H>], method: &Method) -> Vec<H> {
+  hooks
+    .iter()
+    .filter(|h| h.methods.is_empty() || h.methods.contains(method))
+    .map(|h| h.hook.clone())
+    .collect()
+}
+
+/**
+A `Handler` that wraps an `Adapter` and an optional `Channel`, with hooks that run before and after
+them.
+
+`Listen` requests go to the channel, `Action` requests go to the function registered for that
+action with `action`, and every other request goes to the adapter. Requests for actions that
+weren't registered get a `MethodNotAllowed` error. When a `Post`, `Patch`, `Put`, `Delete` or
+`Action` request succeeds, the reply data is published to the channel as a `"created"`,
+`"patched"`, `"updated"`, `"removed"` or action-named event about the object's id, which is kept
+in the adapter's `id_field`. Replies to read-only `GET` actions aren't published.
+
+Hooks are registered for a list of methods, or for every method if the list is empty, and run in
+the order they were added:
+
+- `before` hooks get the `Request`, and can change it or reject it with an `Error`
+- `after` hooks get the `Reply`, and can change it or turn it into an `Error`
+- `error` hooks get any `Error` from the hooks, adapter or channel, and can change it
+
+Resources made with `accept_json_patch` also take `Patch` requests made of JSON Patch operations,
+which `before` hooks see in `Request::json_patch`, with empty request data. Hooks that check what a
+`Patch` request changes, like refusing changes to an `owner` field, have to check both.
+
+```rust,no_run
+# extern crate backtalk;
+# #[macro_use] extern crate serde_json;
+# use backtalk::*;
+# fn main() {
+let mut server = Server::new();
+let cats = Resource::new(memory::MemoryAdapter::new())
+  .channel(memory::MemoryChannel::new())
+  .before(&[Method::Delete], |req: Request| {
+    if req.param("password") != "meow" {
+      return Error::forbidden("incorrect password");
+    }
+    req.boxed()
+  })
+  .action(Action::new("feed"), |req: Request| {
+    let amount = req.data().get("amount").cloned().unwrap_or(json!(1));
+    req.into_reply(json!({"fed": amount}).as_object().unwrap().clone())
+  })
+  .after(&[], |mut reply: Reply| {
+    if let Some(data) = reply.data_mut() {
+      data.remove("secret");
+    }
+    reply
+  });
+server.resource("/cats", cats);
+# }
+```
+*/
+pub struct Resource {
+  adapter: Arc<Adapter>,
+  channel: Option<Arc<Channel>>,
+  before: Vec<Hook<BeforeHook>>,
+  after: Vec<Hook<AfterHook>>,
+  error: Vec<Hook<ErrorHook>>,
+  actions: Vec<(Action, ActionHandler)>,
+  json_patch: bool,
+}
+
+impl Resource {
+  pub fn new<A: Adapter + 'static>(adapter: A) -> Resource {
+    Resource {
+      adapter: Arc::new(adapter),
+      channel: None,
+      before: Vec::new(),
+      after: Vec::new(),
+      error: Vec::new(),
+      actions: Vec::new(),
+      json_patch: false,
+    }
+  }
+
+  /// Sets the `Channel` that handles `Listen` requests, and gets sent changes to the resource.
+  pub fn channel<C: Channel + 'static>(mut self, channel: C) -> Resource {
+    self.channel = Some(Arc::new(channel));
+    self
+  }
+
+  /**
+  Adds an action, which `handler` replies to. The before, after and error hooks for
+  `Method::Action` with the action's name run around it, like for any other method.
+  */
+  pub fn action<F, R>(mut self, action: Action, handler: F) -> Resource
+    where F: Fn(Request) -> R + Send + Sync + 'static,
+          R: IntoFuture<Item=Reply, Error=Error>,
+          R::Future: Send + 'static
+  {
+    self.actions.push((action, Arc::new(move |req| handler(req).into_future().boxed())));
+    self
+  }
+
+  /**
+  Accepts `PATCH` requests with JSON Patch operations, which go to `Adapter::json_patch`. See the
+  `patch` module. `before` hooks for `Method::Patch` should check `Request::json_patch` as well as
+  the request data.
+  */
+  pub fn accept_json_patch(mut self) -> Resource {
+    self.json_patch = true;
+    self
+  }
+
+  // the action a request is for. the server already checked it's reached with the right method.
+  fn find_action(&self, name: &str, id: &Option<String>) -> Option<&(Action, ActionHandler)> {
+    self.actions.iter().find(|&&(ref a, _)| a.name() == name && a.is_collection() == id.is_none())
+  }
+
+  /// Adds a hook that runs on requests with one of `methods`, before they reach the adapter or channel.
+  pub fn before<F, R>(mut self, methods: &[Method], hook: F) -> Resource
+    where F: Fn(Request) -> R + Send + Sync + 'static,
+          R: IntoFuture<Item=Request, Error=Error>,
+          R::Future: Send + 'static
+  {
+    self.before.push(Hook {
+      methods: methods.to_vec(),
+      hook: Arc::new(move |req| hook(req).into_future().boxed()),
+    });
+    self
+  }
+
+  /// Adds a hook that runs on successful replies to requests with one of `methods`.
+  pub fn after<F, R>(mut self, methods: &[Method], hook: F) -> Resource
+    where F: Fn(Reply) -> R + Send + Sync + 'static,
+          R: IntoFuture<Item=Reply, Error=Error>,
+          R::Future: Send + 'static
+  {
+    self.after.push(Hook {
+      methods: methods.to_vec(),
+      hook: Arc::new(move |reply| hook(reply).into_future().boxed()),
+    });
+    self
+  }
+
+  /// Adds a hook that runs on errors from requests with one of `methods`.
+  pub fn error<F>(mut self, methods: &[Method], hook: F) -> Resource
+    where F: Fn(Error) -> Error + Send + Sync + 'static
+  {
+    self.error.push(Hook {
+      methods: methods.to_vec(),
+      hook: Arc::new(hook),
+    });
+    self
+  }
+}
+
+impl Handler for Resource {
+  fn handle(&self, req: Request) -> BoxFuture<Reply, Error> {
+    let method = req.method();
+    let adapter = self.adapter.clone();
+    let listen_channel = self.channel.clone();
+    let send_channel = self.channel.clone();
+    let id_field = self.adapter.id_field();
+    let error_hooks = hooks_for(&self.error, &method);
+    let action = match method {
+      Method::Action(ref name) => self.find_action(name, req.id()).cloned(),
+      _ => None,
+    };
+    // read-only actions don't change anything, so there's nothing to publish
+    let send_channel = match action {
+      Some((ref a, _)) if a.is_get() => None,
+      _ => send_channel,
+    };
+
+    let mut fut = ok(req).boxed();
+    for hook in hooks_for(&self.before, &method) {
+      fut = fut.and_then(move |req| hook(req)).boxed();
+    }
+    let mut fut = fut.and_then(move |req| {
+      match (req.method(), listen_channel) {
+        (Method::Listen, Some(chan)) => chan.handle(req),
+        (Method::Listen, None) => Error::method_not_allowed("this resource doesn't support listening"),
+        (Method::Action(_), _) => match action {
+          Some((_, handler)) => handler(req),
+          None => Error::method_not_allowed("this resource doesn't support that action"),
+        },
+        _ => adapter.handle(req),
+      }
+    }).boxed();
+    for hook in hooks_for(&self.after, &method) {
+      fut = fut.and_then(move |reply| hook(reply)).boxed();
+    }
+    fut.map(move |reply| match send_channel {
+      Some(chan) => publish_reply_with_id_field(reply, &id_field, &*chan),
+      None => reply,
+    }).map_err(move |e| {
+      error_hooks.iter().fold(e, |e, hook| hook(e))
+    }).boxed()
+  }
+
+  fn actions(&self) -> Option<Vec<Action>> {
+    Some(self.actions.iter().map(|&(ref a, _)| a.clone()).collect())
+  }
+
+  fn accepts_json_patch(&self) -> bool {
+    self.json_patch
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use {JsonObject, JsonValue, ErrorKind};
+  use memory::MemoryAdapter;
+
+  fn make_req(m: Method, id: Option<&str>, data: JsonValue) -> Request {
+    let data = match data {
+      JsonValue::Object(o) => o,
+      _ => panic!("not an object"),
+    };
+    Request::new("/cats".to_string(), m, id.map(|s| s.to_string()), data, JsonObject::new())
+  }
+
+  #[test]
+  fn runs_hooks_for_matching_methods() {
+    let resource = Resource::new(MemoryAdapter::new())
+      .before(&[Method::Post], |mut req: Request| {
+        req.data_mut().insert("sound".to_string(), json!("meow"));
+        req
+      })
+      .after(&[], |mut reply: Reply| {
+        reply.data_mut().unwrap().insert("seen".to_string(), json!(true));
+        reply
+      })
+      .before(&[Method::Delete], |_| Error::forbidden("no deleting cats"));
+
+    let reply = resource.handle(make_req(Method::Post, None, json!({"name": "Tom"}))).wait().unwrap();
+    assert_eq!(reply.data().unwrap().get("sound").unwrap(), "meow");
+    assert_eq!(reply.data().unwrap().get("seen").unwrap(), &json!(true));
+    let reply = resource.handle(make_req(Method::Get, Some("1"), json!({}))).wait().unwrap();
+    assert_eq!(reply.data().unwrap().get("seen").unwrap(), &json!(true));
+    let err = resource.handle(make_req(Method::Delete, Some("1"), json!({}))).wait().unwrap_err();
+    assert_eq!(err.status_code(), 403);
+  }
+
+  #[test]
+  fn error_hooks_change_errors() {
+    let resource = Resource::new(MemoryAdapter::new())
+      .error(&[Method::Get], |_| Error::new(ErrorKind::NotFound, json!({"error": "no such cat"})));
+    let err = resource.handle(make_req(Method::Get, Some("1"), json!({}))).wait().unwrap_err();
+    assert_eq!(err.data(), &json!({"error": "no such cat"}));
+    let err = resource.handle(make_req(Method::Listen, None, json!({}))).wait().unwrap_err();
+    assert_eq!(err.status_code(), 405);
+  }
+}
@@ -1,19 +1,29 @@
-use {JsonValue, JsonObject, Reply, Request, Handler, Method, Error, ErrorKind};
-use futures::future::{ok, err};
+use {JsonValue, JsonObject, Reply, Request, Handler, Action, Method, Error, ErrorKind, StreamOptions};
+use futures::future::{ok, err, empty, Shared};
 use futures::{BoxFuture, Future};
-use std::collections::HashMap;
+use futures::sync::oneshot;
 use hyper;
 use hyper::mime;
-use hyper::header::{Accept,q};
+use hyper::header::{Accept, ContentType, Cookie, q};
 use hyper::server as http;
 use hyper::Method as HttpMethod;
 use futures::Stream;
 use futures::future::FutureResult;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use queryst::parse as query_parse;
 use serde_json::value::Map;
-use reply::Body;
+use reply::{self, Body, EventReceiver, take_event_stream};
+use error;
 use serde_json;
+use websocket;
+use poll;
+use futures::{Async, Poll};
+use tokio_core::reactor::{Core, Handle, Interval, Timeout};
+use tokio_core::net::{TcpListener, TcpStream};
+use std::net::{SocketAddr, AddrParseError, TcpListener as StdTcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use std::{error as std_err, fmt, io, thread};
 
 fn std_error(kind: ErrorKind, err_str: &str) -> Error {
   let val = json!({
@@ -41,6 +51,7 @@ pub fn http_to_req(method: &HttpMethod, path: &str, query: &str, headers: &hyper
     }
     (best_qual, is_eventsource)
   });
+  let is_eventsource = is_eventsource || websocket::upgrade_key(method, headers).is_some();
 
   let body = if let Some(b) = body {
     b
@@ -51,7 +62,22 @@ pub fn http_to_req(method: &HttpMethod, path: &str, query: &str, headers: &hyper
     Ok(s) => s,
     _ => return Err(std_error(ErrorKind::BadRequest, "TODO invalid unicode in request body")),
   };
-  let body_obj = if body_str == "" {
+  // a list of JSON Patch operations instead of the usual object, see the `patch` module
+  let is_json_patch = headers.get::<ContentType>()
+    .map(|ct| ct.to_string().starts_with("application/json-patch+json"))
+    .unwrap_or(false);
+  if is_json_patch && method != &HttpMethod::Patch {
+    return Err(std_error(ErrorKind::BadRequest, "JSON Patch can only be sent with PATCH"));
+  }
+  let json_patch: Option<Vec<JsonValue>> = if is_json_patch {
+    match serde_json::from_str(&body_str) {
+      Ok(ops) => Some(ops),
+      _ => return Err(std_error(ErrorKind::BadRequest, "JSON Patch has to be an array of operations")),
+    }
+  } else {
+    None
+  };
+  let body_obj = if body_str == "" || is_json_patch {
     JsonObject::new()
   } else {
     match serde_json::from_str(&body_str) {
@@ -59,7 +85,7 @@ pub fn http_to_req(method: &HttpMethod, path: &str, query: &str, headers: &hyper
       _ => return Err(std_error(ErrorKind::BadRequest, "TODO invalid json in request body")),
     }
   };
-  let query = match query_parse(query) {
+  let mut query = match query_parse(query) {
     Ok(JsonValue::Null) => Map::new(),
     Ok(JsonValue::Object(u)) => u,
     _ => return Err(std_error(ErrorKind::BadRequest, "TODO failed to parse query string"))
@@ -70,176 +96,1116 @@ pub fn http_to_req(method: &HttpMethod, path: &str, query: &str, headers: &hyper
     parts.pop();
   }
 
-  let resource_url = format!("/{}", parts.join("/"));
-  if server.has_resource(&resource_url) {
-    if is_eventsource { // TODO should only work for GET? 403 otherwise? better spec compliance
-      return Ok(Request::new(
-        resource_url,
-        Method::Listen,
-        None,
-        JsonObject::new(),
-        query
-      ))
-    } else if method == &HttpMethod::Get {
-      return Ok(Request::new(
-        resource_url,
-        Method::List,
-        None,
-        JsonObject::new(),
-        query
-      ))
-    } else if method == &HttpMethod::Post {
-      return Ok(Request::new(
-        resource_url,
-        Method::Post,
-        None,
-        body_obj,
-        query
-      ))
-    } else {
-      return Err(std_error(ErrorKind::MethodNotAllowed, "invalid HTTP method for this URL"));
-    }
-  }
-
-  let (id, parts) = match parts.split_last() {
+  let (route, path_params, rest) = match server.router.resolve(&parts) {
     Some(t) => t,
-    None => return Err(std_error(ErrorKind::NotFound, "handler not found"))
+    None => return Err(std_error(ErrorKind::NotFound, "handler not found")),
   };
-  let resource_url = format!("/{}", parts.join("/"));
-  if server.has_resource(&resource_url) {
-    if is_eventsource {
-      return Ok(Request::new(
-        resource_url,
-        Method::Listen,
-        Some(id.to_string()),
-        JsonObject::new(),
-        query
-      ))
-    } else if method == &HttpMethod::Get {
-      return Ok(Request::new(
-        resource_url,
-        Method::Get,
-        Some(id.to_string()),
-        JsonObject::new(),
-        query
-      ))
-    } else if method == &HttpMethod::Patch {
-      return Ok(Request::new(
-        resource_url,
-        Method::Patch,
-        Some(id.to_string()),
-        body_obj,
-        query
-      ))
-    } else if method == &HttpMethod::Delete {
-      return Ok(Request::new(
-        resource_url,
-        Method::Delete,
-        Some(id.to_string()),
-        JsonObject::new(),
-        query
-      ))
-    } else {
-      return Err(std_error(ErrorKind::MethodNotAllowed, "invalid HTTP method for this URL"));
-    }
-  }
-
-  let action_name = id;
-  let (id, parts) = match parts.split_last() {
-    Some(t) => t,
-    None => return Err(std_error(ErrorKind::NotFound, "handler not found"))
+  let resource_url = route.pattern.clone();
+  // `?poll=<cursor>` is a long-polling `Listen`, and isn't passed on as a param
+  let is_poll = query.remove("poll").is_some();
+  let is_eventsource = is_eventsource || (method == &HttpMethod::Get && is_poll);
+  // path parameters like `:user_id` take precedence over query parameters of the same name
+  let mut params = query;
+  for (key, val) in path_params.into_iter() {
+    params.insert(key, val);
+  }
+
+  let actions = route.handler.actions();
+  if is_json_patch && !route.handler.accepts_json_patch() {
+    return Err(std_error(ErrorKind::BadRequest, "this resource doesn't accept JSON Patch"));
+  }
+
+  // sent by event-stream clients when they reconnect
+  let last_event_id = headers
+    .get_raw("Last-Event-ID")
+    .and_then(|raw| raw.one())
+    .and_then(|bytes| String::from_utf8(bytes.to_vec()).ok());
+
+  let req = match rest.len() {
+    0 => {
+      if is_eventsource { // TODO should only work for GET? 403 otherwise? better spec compliance
+        Ok(Request::new(
+          resource_url,
+          Method::Listen,
+          None,
+          JsonObject::new(),
+          params
+        ))
+      } else if method == &HttpMethod::Get {
+        Ok(Request::new(
+          resource_url,
+          Method::List,
+          None,
+          JsonObject::new(),
+          params
+        ))
+      } else if method == &HttpMethod::Post {
+        Ok(Request::new(
+          resource_url,
+          Method::Post,
+          None,
+          body_obj,
+          params
+        ))
+      } else {
+        Err(std_error(ErrorKind::MethodNotAllowed, "invalid HTTP method for this URL"))
+      }
+    },
+    1 => {
+      let id = rest[0];
+      if is_eventsource {
+        Ok(Request::new(
+          resource_url,
+          Method::Listen,
+          Some(id.to_string()),
+          JsonObject::new(),
+          params
+        ))
+      } else if method == &HttpMethod::Get && declares_action(&actions, id, true, true) {
+        Ok(Request::new(
+          resource_url,
+          Method::Action(id.to_string()),
+          None,
+          body_obj,
+          params
+        ))
+      } else if method == &HttpMethod::Get {
+        Ok(Request::new(
+          resource_url,
+          Method::Get,
+          Some(id.to_string()),
+          JsonObject::new(),
+          params
+        ))
+      } else if method == &HttpMethod::Patch {
+        Ok(Request::new(
+          resource_url,
+          Method::Patch,
+          Some(id.to_string()),
+          body_obj,
+          params
+        ))
+      } else if method == &HttpMethod::Put {
+        Ok(Request::new(
+          resource_url,
+          Method::Put,
+          Some(id.to_string()),
+          body_obj,
+          params
+        ))
+      } else if method == &HttpMethod::Delete {
+        Ok(Request::new(
+          resource_url,
+          Method::Delete,
+          Some(id.to_string()),
+          JsonObject::new(),
+          params
+        ))
+      } else if method == &HttpMethod::Post {
+        // an action on the whole collection
+        let action_name = id;
+        if !declares_action(&actions, action_name, true, false) {
+          Err(std_error(ErrorKind::MethodNotAllowed, "this resource doesn't support that action"))
+        } else {
+          Ok(Request::new(
+            resource_url,
+            Method::Action(action_name.to_string()),
+            None,
+            body_obj,
+            params
+          ))
+        }
+      } else {
+        Err(std_error(ErrorKind::MethodNotAllowed, "invalid HTTP method for this URL"))
+      }
+    },
+    _ => {
+      let (id, action_name) = (rest[0], rest[1]);
+      let is_get = method == &HttpMethod::Get;
+      if !is_get && method != &HttpMethod::Post {
+        Err(std_error(ErrorKind::MethodNotAllowed, "invalid HTTP method for this URL"))
+      } else if (actions.is_some() || is_get) && !declares_action(&actions, action_name, false, is_get) {
+        Err(std_error(ErrorKind::MethodNotAllowed, "this resource doesn't support that action"))
+      } else {
+        Ok(Request::new(
+          resource_url,
+          Method::Action(action_name.to_string()),
+          Some(id.to_string()),
+          body_obj,
+          params
+        ))
+      }
+    },
   };
-  let resource_url = format!("/{}", parts.join("/"));
-  if server.has_resource(&resource_url) {
-    if method == &HttpMethod::Post {
-      return Ok(Request::new(
-        resource_url,
-        Method::Action(action_name.to_string()),
-        Some(id.to_string()),
-        JsonObject::new(),
-        query
-      ))
-    } else {
-      return Err(std_error(ErrorKind::MethodNotAllowed, "invalid HTTP method for this URL"));
+  req.map(|mut req| {
+    if req.method() == Method::Listen {
+      req.set_last_event_id(last_event_id);
     }
+    if req.method() == Method::Patch {
+      req.set_json_patch(json_patch);
+    }
+    read_headers(&mut req, headers);
+    req
+  })
+}
+
+// only used internally, copies the headers and cookies onto the request
+pub fn read_headers(req: &mut Request, headers: &hyper::Headers) {
+  for header in headers.iter() {
+    let lines: Vec<String> = header.raw().iter().map(|line| String::from_utf8_lossy(line).into_owned()).collect();
+    if header.is::<Cookie>() {
+      for line in &lines {
+        for pair in line.split(';') {
+          let mut pair = pair.splitn(2, '=');
+          let (name, val) = match (pair.next(), pair.next()) {
+            (Some(name), Some(val)) => (name.trim(), val.trim().trim_matches('"')),
+            _ => continue,
+          };
+          if name != "" {
+            req.set_cookie(name.to_string(), JsonValue::String(val.to_string()));
+          }
+        }
+      }
+    }
+    req.set_header(header.name(), JsonValue::String(lines.join(", ")));
+  }
+}
+
+fn declares_action(actions: &Option<Vec<Action>>, name: &str, collection: bool, get: bool) -> bool {
+  match actions {
+    &Some(ref actions) => actions.iter().any(|a| a.name() == name && a.is_collection() == collection && a.is_get() == get),
+    &None => false,
   }
+}
 
-  Err(std_error(ErrorKind::NotFound, "handler not found"))
+/// A single piece of a route pattern, split on `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+  Literal(String),
+  Param(String),
 }
 
-// only one is created
-#[derive(Clone)]
+impl Segment {
+  fn is_literal(&self) -> bool {
+    match self {
+      &Segment::Literal(_) => true,
+      &Segment::Param(_) => false,
+    }
+  }
+
+  // two segments have the same shape if they'd match exactly the same path segments
+  fn same_shape(&self, other: &Segment) -> bool {
+    match (self, other) {
+      (&Segment::Literal(ref a), &Segment::Literal(ref b)) => a == b,
+      (&Segment::Param(_), &Segment::Param(_)) => true,
+      _ => false,
+    }
+  }
+}
+
+struct Route {
+  pattern: String,
+  segments: Vec<Segment>,
+  handler: Box<Handler>,
+}
+
+impl Route {
+  fn new(pattern: &str, handler: Box<Handler>) -> Route {
+    let segments: Vec<Segment> = pattern
+      .split("/")
+      .filter(|s| s != &"")
+      .map(|s| if s.starts_with(":") {
+        Segment::Param(s[1..].to_string())
+      } else {
+        Segment::Literal(s.to_string())
+      })
+      .collect();
+    let pattern = format!("/{}", segments.iter().map(|seg| match seg {
+      &Segment::Literal(ref s) => s.clone(),
+      &Segment::Param(ref s) => format!(":{}", s),
+    }).collect::<Vec<String>>().join("/"));
+    Route {
+      pattern: pattern,
+      segments: segments,
+      handler: handler,
+    }
+  }
+
+  fn same_shape(&self, other: &Route) -> bool {
+    self.segments.len() == other.segments.len() &&
+      self.segments.iter().zip(other.segments.iter()).all(|(a, b)| a.same_shape(b))
+  }
+
+  // returns the path parameters if `parts` matches this route exactly
+  fn matches(&self, parts: &[&str]) -> Option<JsonObject> {
+    if parts.len() != self.segments.len() {
+      return None;
+    }
+    let mut params = JsonObject::new();
+    for (seg, part) in self.segments.iter().zip(parts.iter()) {
+      match seg {
+        &Segment::Literal(ref s) => if s != part {
+          return None;
+        },
+        &Segment::Param(ref name) => {
+          params.insert(name.clone(), JsonValue::String(part.to_string()));
+        },
+      }
+    }
+    Some(params)
+  }
+}
+
+/**
+Matches URL paths against route patterns like `/users/:user_id/cats`.
+
+When more than one route could match a path, the router prefers, in order:
+
+1. a route matching the whole path, then one leaving an id, then one leaving an id and an action
+2. among those, the route whose first differing segment is a literal instead of a `:param`
+*/
+struct Router {
+  routes: Vec<Route>,
+}
+
+impl Router {
+  fn new() -> Router {
+    Router {
+      routes: Vec::new(),
+    }
+  }
+
+  // a route with the same shape as an existing one replaces it, even if the param names differ
+  fn insert(&mut self, route: Route) {
+    match self.routes.iter().position(|r| r.same_shape(&route)) {
+      Some(i) => self.routes[i] = route,
+      None => self.routes.push(route),
+    }
+  }
+
+  fn get(&self, pattern: &str) -> Option<&Route> {
+    self.routes.iter().find(|r| r.pattern == pattern)
+  }
+
+  fn resolve<'a, 'b>(&'a self, parts: &'b [&'b str]) -> Option<(&'a Route, JsonObject, &'b [&'b str])> {
+    for trailing in 0..3 {
+      if trailing > parts.len() {
+        break;
+      }
+      let (prefix, rest) = parts.split_at(parts.len() - trailing);
+      let best = self.routes
+        .iter()
+        .filter_map(|r| r.matches(prefix).map(|params| (r, params)))
+        .max_by_key(|&(r, _)| r.segments.iter().map(|s| s.is_literal()).collect::<Vec<bool>>());
+      if let Some((route, params)) = best {
+        return Some((route, params, rest));
+      }
+    }
+    None
+  }
+}
+
+// resolves once the server starts shutting down, shared between all the worker threads
+type Shutdown = Shared<Box<Future<Item=(), Error=()> + Send>>;
+
+// counts as an in-flight request until it's dropped
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+  fn new(counter: &Arc<AtomicUsize>) -> InFlight {
+    counter.fetch_add(1, Ordering::SeqCst);
+    InFlight(counter.clone())
+  }
+}
+
+impl Drop for InFlight {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+// a response body that keeps its request in flight until the body is fully sent. event streams
+// start with their `retry:` field, send heartbeats while idle, and get a final `shutdown` event
+// and end when the server shuts down.
+struct TrackedBody {
+  body: Body,
+  shutdown: Shutdown,
+  done: bool,
+  _in_flight: InFlight,
+  retry: Option<hyper::Chunk>,
+  heartbeat: Option<Interval>,
+}
+
+impl Stream for TrackedBody {
+  type Item = hyper::Chunk;
+  type Error = hyper::Error;
+
+  fn poll(&mut self) -> Poll<Option<hyper::Chunk>, hyper::Error> {
+    if self.done {
+      return Ok(Async::Ready(None));
+    }
+    if let Body::Stream(_) = self.body {
+      match self.shutdown.poll() {
+        Ok(Async::NotReady) => (),
+        _ => {
+          self.done = true;
+          return Ok(Async::Ready(Some("event:shutdown\ndata:{}\n\n".into())));
+        },
+      }
+      if let Some(chunk) = self.retry.take() {
+        return Ok(Async::Ready(Some(chunk)));
+      }
+    }
+    match self.body.poll() {
+      Ok(Async::NotReady) => (),
+      res => return res,
+    }
+    let beat = match self.heartbeat {
+      Some(ref mut interval) => interval.poll(),
+      None => return Ok(Async::NotReady),
+    };
+    match beat {
+      Ok(Async::Ready(Some(()))) => Ok(Async::Ready(Some(":keepalive\n\n".into()))),
+      Ok(_) => Ok(Async::NotReady),
+      Err(_) => {
+        // the timer is broken, so give up on heartbeats for this stream
+        self.heartbeat = None;
+        Ok(Async::NotReady)
+      },
+    }
+  }
+}
+
+// fills in the settings a channel left out with the server's
+fn merge_options(channel: StreamOptions, server: StreamOptions) -> StreamOptions {
+  StreamOptions {
+    heartbeat: channel.heartbeat.or(server.heartbeat),
+    retry: channel.retry.or(server.retry),
+  }
+}
+
+// a zero heartbeat means the channel turned heartbeats off
+fn heartbeat_interval(options: StreamOptions, handle: &Handle) -> Option<Interval> {
+  match options.heartbeat {
+    Some(d) if d != Duration::new(0, 0) => Interval::new(d, handle).ok(),
+    _ => None,
+  }
+}
+
+// one is created per connection
 struct HttpService {
   server: Arc<Server>,
+  // set when a `Listen` request upgrades this connection to a websocket, with the request's headers
+  upgrade: Arc<Mutex<Option<(EventReceiver, hyper::Headers)>>>,
+  remote_addr: Option<SocketAddr>,
+  in_flight: Arc<AtomicUsize>,
+  shutdown: Shutdown,
+  handle: Handle,
 }
 
 impl http::Service for HttpService {
   type Request = http::Request;
-  type Response = http::Response<Body>;
+  type Response = http::Response<TrackedBody>;
   type Error = hyper::Error;
-  type Future = BoxFuture<Self::Response, Self::Error>;
+  type Future = Box<Future<Item=Self::Response, Error=Self::Error>>;
 
   fn call(&self, http_req: http::Request) -> Self::Future {
     let (method, uri, _, headers, body) = http_req.deconstruct();
 
     let server = self.server.clone();
+    let upgrade = self.upgrade.clone();
+    let in_flight = InFlight::new(&self.in_flight);
+    let shutdown = self.shutdown.clone();
+    let handle = self.handle.clone();
+    let poll_handle = self.handle.clone();
+    let defaults = self.server.stream_options;
+    let ws_key = websocket::upgrade_key(&method, &headers);
+    let upgrade_headers = headers.clone();
+    let remote_addr = self.remote_addr;
+    let poll_cursor = match (&method, &ws_key) {
+      (&HttpMethod::Get, &None) => poll::cursor(uri.query().unwrap_or("")),
+      _ => None,
+    };
+    let poll_shutdown = Box::new(self.shutdown.clone().then(|_| Ok(())));
     let body_prom = body.fold(Vec::new(), |mut a, b| -> FutureResult<Vec<u8>, hyper::Error> { a.extend_from_slice(&b[..]); ok(a) });
 
-    body_prom.then(move |body_res| {
-      match http_to_req(&method, uri.path(), uri.query().unwrap_or(""), &headers, body_res.ok(), &server) {
-        Ok(req) => server.handle(req),
-        Err(reply) => err(reply).boxed(),
+    Box::new(body_prom.then(move |body_res| {
+      let req = http_to_req(&method, uri.path(), uri.query().unwrap_or(""), &headers, body_res.ok(), &server).map(|mut req| {
+        req.set_remote_addr(remote_addr);
+        req
+      });
+      match (req, poll_cursor) {
+        (Ok(req), Some(ref cursor)) if cursor.is_empty() => server.polls.start(server.handle(req), server.poll_timeout, &poll_handle),
+        (Ok(req), Some(cursor)) => server.polls.poll(req, &cursor, server.poll_timeout, poll_shutdown, &poll_handle),
+        (Ok(req), None) => Box::new(server.handle(req)),
+        (Err(reply), _) => Box::new(err(reply)),
       }
-    }).then(|reply| {
-      let http_resp = match reply {
-        Ok(r) => r.to_http(),
-        Err(r) => r.to_http(),
+    }).then(move |reply| {
+      let options = match reply {
+        Ok(ref r) => reply::stream_options(r).map(|o| merge_options(o, defaults)),
+        Err(_) => None,
+      }.unwrap_or_default();
+      let (http_resp, body) = match (reply, ws_key) {
+        (Ok(r), Some(key)) => match take_event_stream(r) {
+          Ok(events) => {
+            *upgrade.lock().unwrap() = Some((events, upgrade_headers));
+            (websocket::handshake_response(&key), Body::Once(None))
+          },
+          Err(r) => reply::to_http_parts(r),
+        },
+        (Ok(r), None) => reply::to_http_parts(r),
+        (Err(r), _) => error::to_http_parts(r),
       };
-      ok(http_resp)
-    }).boxed()
+      let retry = options.retry.map(|d| format!("retry:{}\n\n", millis(d)).into());
+      let heartbeat = heartbeat_interval(options, &handle);
+      ok(http_resp.with_body(TrackedBody {
+        body: body,
+        shutdown: shutdown,
+        done: false,
+        _in_flight: in_flight,
+        retry: retry,
+        heartbeat: heartbeat,
+      }))
+    }))
   }
 }
 
+fn millis(d: Duration) -> u64 {
+  d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
+
+// resolves to the connection's parts once hyper is done with it, without closing the socket
+struct ConnectionDone {
+  conn: Option<http::Connection<TcpStream, HttpService>>,
+}
+
+impl Future for ConnectionDone {
+  type Item = http::conn::Parts<TcpStream, HttpService>;
+  type Error = hyper::Error;
+
+  fn poll(&mut self) -> Poll<Self::Item, hyper::Error> {
+    try_ready!(self.conn.as_mut().expect("polled ConnectionDone after completion").poll_without_shutdown());
+    Ok(Async::Ready(self.conn.take().unwrap().into_parts()))
+  }
+}
+
+fn serve_connection(protocol: &http::Http<hyper::Chunk>, sock: TcpStream, server: Arc<Server>, in_flight: Arc<AtomicUsize>, shutdown: Shutdown, handle: Handle) -> Box<Future<Item=(), Error=()>> {
+  let upgrade = Arc::new(Mutex::new(None));
+  let remote_addr = sock.peer_addr().ok();
+  let service = HttpService {
+    server: server.clone(),
+    upgrade: upgrade.clone(),
+    remote_addr: remote_addr,
+    in_flight: in_flight.clone(),
+    shutdown: shutdown.clone(),
+    handle: handle.clone(),
+  };
+  let conn = ConnectionDone {
+    conn: Some(protocol.serve_connection(sock, service)),
+  };
+  Box::new(conn.then(move |res| -> Box<Future<Item=(), Error=()>> {
+    // dropping the parts closes the socket, unless we hand it to the websocket
+    match (res, upgrade.lock().unwrap().take()) {
+      (Ok(parts), Some((events, headers))) => {
+        let in_flight = InFlight::new(&in_flight);
+        let shutdown = Box::new(shutdown.then(|_| Ok(())));
+        Box::new(websocket::serve(parts.io, parts.read_buf, events, server, headers, remote_addr, shutdown, &handle).then(move |res| {
+          drop(in_flight);
+          res
+        }))
+      },
+      _ => Box::new(ok(())),
+    }
+  }))
+}
+
 /**
 Routes requests to various `Handler`s based on the request URL, and runs the actual HTTP server
 and async event loop.
+
+Routes may contain parameters, like `/users/:user_id/cats`. A request to `/users/12/cats/3` would
+be passed to that route's handler as a `Get` request with an id of `"3"`, and with the param
+`user_id` set to `"12"`. The request's `resource()` is the route pattern itself.
 */
 pub struct Server {
-  route_table: HashMap<String, Box<Handler>>
+  router: Router,
+  threads: usize,
+  shutdown_timeout: Duration,
+  stream_options: StreamOptions,
+  poll_timeout: Duration,
+  polls: poll::Sessions,
 }
 
 impl Server {
   pub fn new() -> Server {
     Server{
-      route_table: HashMap::new()
+      router: Router::new(),
+      threads: 1,
+      shutdown_timeout: Duration::from_secs(10),
+      stream_options: StreamOptions {
+        heartbeat: Some(Duration::from_secs(30)),
+        retry: None,
+      },
+      poll_timeout: Duration::from_secs(30),
+      polls: poll::Sessions::new(),
     }
   }
 
-  fn has_resource(&self, s: &str) -> bool {
-    self.route_table.get(s).is_some()
-  }
-
   pub fn handle(&self, req: Request) -> BoxFuture<Reply, Error> {
-    // TODO maybe instead do some sort of indexing instead of all this string hashing, so like, the webhooks calls get_route_ref or something
-    match self.route_table.get(req.resource()) {
-      Some(resource) => resource.handle(req),
+    // TODO maybe instead do some sort of indexing instead of all this string comparison, so like, the webhooks calls get_route_ref or something
+    match self.router.get(req.resource()) {
+      Some(route) => route.handler.handle(req),
       None => err(Error::new(ErrorKind::NotFound, JsonValue::String("TODO not found error here".to_string()))).boxed()
     }
   }
 
+  /**
+  Adds a `Handler` for the given route. Segments of the route starting with `:` are parameters,
+  which match any single path segment and are added to the request params. Adding a route with
+  the same shape as an existing one (like `/users/:id` and `/users/:user_id`) replaces it.
+  */
   pub fn resource<T: Into<String>, R: Handler + 'static>(&mut self, route: T, handler: R) {
-    self.route_table.insert(route.into(), Box::new(handler));
+    let route: String = route.into();
+    self.router.insert(Route::new(&route, Box::new(handler)));
+  }
+
+  /**
+  Sets the number of threads `listen` runs the server on. Each thread has its own event loop, and
+  they all accept connections from the same listening socket. Defaults to 1. Panics if `count` is 0.
+  */
+  pub fn threads(&mut self, count: usize) {
+    assert!(count > 0, "a server needs at least one thread");
+    self.threads = count;
+  }
+
+  /**
+  Sets how long `listen_until` waits for in-flight requests to finish after it's told to shut
+  down, before giving up on them. Defaults to 10 seconds.
+  */
+  pub fn shutdown_timeout(&mut self, timeout: Duration) {
+    self.shutdown_timeout = timeout;
+  }
+
+  /**
+  Sets how often event streams send a `:keepalive` comment while they're idle, so proxies and load
+  balancers don't close them. `None` or a zero duration turns heartbeats off. Defaults to every 30
+  seconds. A `Channel` can override this with `Channel::stream_options`, and turn them off by
+  setting its heartbeat to zero.
+  */
+  pub fn heartbeat(&mut self, interval: Option<Duration>) {
+    self.stream_options.heartbeat = interval;
+  }
+
+  /**
+  Sets the `retry:` field sent at the start of event streams, which tells clients how long to wait
+  before reconnecting. `None` leaves it to the client. Defaults to `None`. A `Channel` can override
+  this with `Channel::stream_options`.
+  */
+  pub fn retry(&mut self, delay: Option<Duration>) {
+    self.stream_options.retry = delay;
   }
 
+  /**
+  Sets how long a long-polling `GET /resource?poll=<cursor>` request waits for events before
+  replying without any. Defaults to 30 seconds. Clients that don't poll again within twice this
+  long have to start over.
+  */
+  pub fn poll_timeout(&mut self, timeout: Duration) {
+    self.poll_timeout = timeout;
+  }
+
+  /// Runs the server forever, panicking if it can't start. See `listen_until` for a version that
+  /// returns errors and can be shut down.
   pub fn listen<T: Into<String> + Send + 'static>(self, bind_addr: T) {
+    self.listen_until(bind_addr, empty()).unwrap();
+  }
+
+  /**
+  Runs the server until the `shutdown` future resolves (or fails), then shuts it down gracefully.
+
+  On shutdown, the server stops accepting connections, and waits for in-flight requests to finish,
+  for up to the `shutdown_timeout`. Event streams and WebSockets that are listening to a `Channel`
+  are sent a final `shutdown` event, then closed. Returns an error if the address is invalid, if
+  the server couldn't bind to it or accept connections, or if a worker thread panicked. When one
+  worker fails, the others shut down too.
+
+  ```rust,no_run
+  # extern crate backtalk;
+  # extern crate futures;
+  # use backtalk::*;
+  # use futures::Future;
+  # fn main() {
+  let server = Server::new();
+  let (tx, rx) = futures::sync::oneshot::channel::<()>();
+  // call `tx.send(())` from another thread, for instance on SIGTERM, to shut down
+  # drop(tx);
+  server.listen_until("127.0.0.1:3000", rx.map_err(|_| ())).unwrap();
+  # }
+  ```
+  */
+  pub fn listen_until<T, F>(self, bind_addr: T, shutdown: F) -> Result<(), ListenError>
+    where T: Into<String> + Send + 'static, F: Future<Item=(), Error=()> + Send + 'static
+  {
     let addr: String = bind_addr.into();
-    let http_addr = addr.as_str().parse().unwrap();
+    let http_addr: SocketAddr = addr.as_str().parse()?;
+    let listener = StdTcpListener::bind(&http_addr)?;
+    let threads = self.threads;
+    let timeout = self.shutdown_timeout;
+    println!("Listening on http://{} with {} thread{}.", listener.local_addr()?, threads, if threads == 1 { "" } else { "s" });
+    // lets any worker stop the others if it fails
+    let (abort_tx, abort_rx) = oneshot::channel::<()>();
+    let abort = Abort(Arc::new(Mutex::new(Some(abort_tx))));
+    let shutdown = shutdown.select(abort_rx.map_err(|_| ())).map(|_| ()).map_err(|_| ());
+    let shutdown = (Box::new(shutdown) as Box<Future<Item=(), Error=()> + Send>).shared();
     let server_arc = Arc::new(self);
-    let server_clone = server_arc.clone();
-    let server = http::Http::new().bind::<_, Body>(&http_addr, move || {
-      Ok(HttpService{server: (&server_clone).clone()})
-    }).unwrap();
-    println!("Listening on http://{} with 1 thread.", server.local_addr().unwrap());
-    server.run().unwrap();
+    let mut workers: Vec<thread::JoinHandle<Result<(), ListenError>>> = Vec::new();
+    let mut res = Ok(());
+    for i in 0..threads {
+      let server = server_arc.clone();
+      let shutdown = shutdown.clone();
+      let worker_abort = abort.clone();
+      let spawned = listener.try_clone().and_then(|listener| thread::Builder::new()
+        .name(format!("backtalk-worker-{}", i))
+        .spawn(move || {
+          let _on_panic = AbortOnPanic(worker_abort.clone());
+          let res = run_worker(listener, server, shutdown, timeout);
+          if res.is_err() {
+            worker_abort.abort();
+          }
+          res
+        }));
+      match spawned {
+        Ok(worker) => workers.push(worker),
+        Err(e) => {
+          abort.abort();
+          res = Err(ListenError::Io(e));
+          break;
+        },
+      }
+    }
+    drop(listener);
+    workers
+      .into_iter()
+      .map(|worker| worker.join().unwrap_or(Err(ListenError::WorkerPanicked)))
+      .fold(res, |a, b| a.and(b))
+  }
+}
+
+// stops all the workers, once one of them fails
+#[derive(Clone)]
+struct Abort(Arc<Mutex<Option<oneshot::Sender<()>>>>);
+
+impl Abort {
+  fn abort(&self) {
+    if let Some(tx) = self.0.lock().unwrap().take() {
+      let _ = tx.send(());
+    }
+  }
+}
+
+// aborts if the worker it's dropped by panicked
+struct AbortOnPanic(Abort);
+
+impl Drop for AbortOnPanic {
+  fn drop(&mut self) {
+    if thread::panicking() {
+      self.0.abort();
+    }
+  }
+}
+
+// runs an event loop accepting connections on this thread until shutdown, then waits for the
+// in-flight requests on this thread to finish
+fn run_worker(listener: StdTcpListener, server: Arc<Server>, shutdown: Shutdown, timeout: Duration) -> Result<(), ListenError> {
+  let mut core = Core::new()?;
+  let handle = core.handle();
+  let addr = listener.local_addr()?;
+  let listener = TcpListener::from_listener(listener, &addr, &handle)?;
+  let protocol = http::Http::new();
+  let in_flight = Arc::new(AtomicUsize::new(0));
+
+  let accept_handle = handle.clone();
+  let accept_in_flight = in_flight.clone();
+  let accept_shutdown = shutdown.clone();
+  let accept = listener.incoming().for_each(move |(sock, _)| {
+    accept_handle.spawn(serve_connection(&protocol, sock, server.clone(), accept_in_flight.clone(), accept_shutdown.clone(), accept_handle.clone()));
+    Ok(())
+  });
+  // dropping the listener stops accepting connections
+  let stopped = shutdown.then(|_| Ok(()));
+  core.run(accept.select(stopped).map(|_| ()).map_err(|(e, _)| e))?;
+
+  let drained = Interval::new(Duration::from_millis(50), &handle)?
+    .take_while(move |_| Ok(in_flight.load(Ordering::SeqCst) > 0))
+    .for_each(|_| Ok(()));
+  core.run(drained.select(Timeout::new(timeout, &handle)?).map(|_| ()).map_err(|(e, _)| e))?;
+  Ok(())
+}
+
+/// An error that stopped `Server::listen_until` from running the server.
+#[derive(Debug)]
+pub enum ListenError {
+  /// The address passed to `listen_until` isn't a valid socket address, like `"127.0.0.1:3000"`.
+  Address(AddrParseError),
+  /// The server couldn't bind to the address, or failed while accepting connections.
+  Io(io::Error),
+  /// One of the server's worker threads panicked, for instance in a request handler.
+  WorkerPanicked,
+}
+
+impl fmt::Display for ListenError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      &ListenError::Address(ref e) => write!(f, "invalid address to listen on: {}", e),
+      &ListenError::Io(ref e) => write!(f, "server IO error: {}", e),
+      &ListenError::WorkerPanicked => write!(f, "a server worker thread panicked"),
+    }
+  }
+}
+
+impl std_err::Error for ListenError {
+  fn description(&self) -> &str {
+    match self {
+      &ListenError::Address(_) => "invalid address to listen on",
+      &ListenError::Io(_) => "server IO error",
+      &ListenError::WorkerPanicked => "a server worker thread panicked",
+    }
+  }
+
+  fn cause(&self) -> Option<&std_err::Error> {
+    match self {
+      &ListenError::Address(ref e) => Some(e),
+      &ListenError::Io(ref e) => Some(e),
+      &ListenError::WorkerPanicked => None,
+    }
+  }
+}
+
+impl From<AddrParseError> for ListenError {
+  fn from(e: AddrParseError) -> ListenError {
+    ListenError::Address(e)
+  }
+}
+
+impl From<io::Error> for ListenError {
+  fn from(e: io::Error) -> ListenError {
+    ListenError::Io(e)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::future::FutureResult;
+
+  fn echo(req: Request) -> FutureResult<Reply, Error> {
+    ok(req.into_reply(JsonObject::new()))
+  }
+
+  fn make_server(routes: &[&str]) -> Arc<Server> {
+    let mut server = Server::new();
+    for route in routes {
+      server.resource(*route, echo);
+    }
+    Arc::new(server)
+  }
+
+  fn to_req(server: &Arc<Server>, method: HttpMethod, path: &str) -> Result<Request, Error> {
+    http_to_req(&method, path, "", &hyper::Headers::new(), Some(Vec::new()), server)
+  }
+
+  #[test]
+  fn routes_plain_resources() {
+    let server = make_server(&["/cats"]);
+    let req = to_req(&server, HttpMethod::Get, "/cats").unwrap();
+    assert_eq!(req.resource(), "/cats");
+    assert_eq!(req.method(), Method::List);
+    let req = to_req(&server, HttpMethod::Get, "/cats/12/").unwrap();
+    assert_eq!(req.method(), Method::Get);
+    assert_eq!(req.id(), &Some("12".to_string()));
+    let req = to_req(&server, HttpMethod::Put, "/cats/12").unwrap();
+    assert_eq!(req.method(), Method::Put);
+    assert_eq!(req.id(), &Some("12".to_string()));
+    let req = to_req(&server, HttpMethod::Post, "/cats/12/feed").unwrap();
+    assert_eq!(req.method(), Method::Action("feed".to_string()));
+    assert!(to_req(&server, HttpMethod::Get, "/dogs").is_err());
+    assert!(to_req(&server, HttpMethod::Get, "/cats/12/feed/more").is_err());
+  }
+
+  #[test]
+  fn reads_json_patch_bodies() {
+    let mut server = Server::new();
+    server.resource("/cats", ::Resource::new(::memory::MemoryAdapter::new()).accept_json_patch());
+    server.resource("/dogs", ::Resource::new(::memory::MemoryAdapter::new()));
+    let server = Arc::new(server);
+    let mut headers = hyper::Headers::new();
+    headers.set_raw("Content-Type", "application/json-patch+json");
+    let body = br#"[{"op": "remove", "path": "/name"}]"#.to_vec();
+    let req = http_to_req(&HttpMethod::Patch, "/cats/12", "", &headers, Some(body.clone()), &server).unwrap();
+    assert_eq!(req.json_patch(), Some(&[json!({"op": "remove", "path": "/name"})][..]));
+    assert!(req.data().is_empty());
+    assert!(http_to_req(&HttpMethod::Post, "/cats", "", &headers, Some(body), &server).is_err());
+    assert!(http_to_req(&HttpMethod::Patch, "/cats/12", "", &headers, Some(b"{}".to_vec()), &server).is_err());
+    let req = to_req(&server, HttpMethod::Patch, "/cats/12").unwrap();
+    assert_eq!(req.json_patch(), None);
+    // resources have to opt in, so hooks that only check the data can't be bypassed
+    let err = http_to_req(&HttpMethod::Patch, "/dogs/12", "", &headers, Some(b"[]".to_vec()), &server).err().unwrap();
+    assert_eq!(err.status_code(), 400);
+  }
+
+  #[test]
+  fn routes_path_params() {
+    let server = make_server(&["/users/:user_id/cats"]);
+    let req = to_req(&server, HttpMethod::Get, "/users/5/cats/12").unwrap();
+    assert_eq!(req.resource(), "/users/:user_id/cats");
+    assert_eq!(req.method(), Method::Get);
+    assert_eq!(req.id(), &Some("12".to_string()));
+    assert_eq!(req.param("user_id"), "5");
+    let req = to_req(&server, HttpMethod::Get, "/users/5/cats").unwrap();
+    assert_eq!(req.method(), Method::List);
+    assert!(server.handle(req).wait().is_ok());
+  }
+
+  #[test]
+  fn path_params_override_query() {
+    let server = make_server(&["/users/:user_id/cats"]);
+    let req = http_to_req(&HttpMethod::Get, "/users/5/cats", "user_id=6&color=grey", &hyper::Headers::new(), Some(Vec::new()), &server).unwrap();
+    assert_eq!(req.param("user_id"), "5");
+    assert_eq!(req.param("color"), "grey");
+  }
+
+  #[test]
+  fn prefers_literal_segments() {
+    let server = make_server(&["/users/:user_id/cats", "/users/me/cats"]);
+    let req = to_req(&server, HttpMethod::Get, "/users/me/cats").unwrap();
+    assert_eq!(req.resource(), "/users/me/cats");
+    assert_eq!(req.param("user_id"), &JsonValue::Null);
+    let req = to_req(&server, HttpMethod::Get, "/users/you/cats").unwrap();
+    assert_eq!(req.resource(), "/users/:user_id/cats");
+  }
+
+  #[test]
+  fn prefers_longer_matches() {
+    // `/users/5/cats` could also be the action `cats` on user 5
+    let server = make_server(&["/users", "/users/:user_id/cats"]);
+    let req = to_req(&server, HttpMethod::Post, "/users/5/cats").unwrap();
+    assert_eq!(req.resource(), "/users/:user_id/cats");
+    assert_eq!(req.method(), Method::Post);
+    let req = to_req(&server, HttpMethod::Post, "/users/5/feed").unwrap();
+    assert_eq!(req.resource(), "/users");
+    assert_eq!(req.method(), Method::Action("feed".to_string()));
+    let req = to_req(&server, HttpMethod::Get, "/users/5/cats/12").unwrap();
+    assert_eq!(req.resource(), "/users/:user_id/cats");
+  }
+
+  #[test]
+  fn same_shape_routes_replace() {
+    let server = make_server(&["/users/:id/cats", "/users/:user_id/cats/"]);
+    assert_eq!(server.router.routes.len(), 1);
+    let req = to_req(&server, HttpMethod::Get, "/users/5/cats").unwrap();
+    assert_eq!(req.resource(), "/users/:user_id/cats");
+    assert_eq!(req.param("user_id"), "5");
+  }
+
+  #[test]
+  fn listen_until_returns_errors() {
+    match Server::new().listen_until("not an address", empty()) {
+      Err(ListenError::Address(_)) => (),
+      res => panic!("expected an address error, got {:?}", res),
+    }
+  }
+
+  #[test]
+  fn listen_until_stops_on_shutdown() {
+    assert!(Server::new().listen_until("127.0.0.1:0", ok(())).is_ok());
+  }
+
+  // sends a raw HTTP request, for tests that need a real connection
+  fn send_raw(addr: SocketAddr, path: &str, accept: &str) -> ::std::net::TcpStream {
+    use std::io::Write;
+    let mut stream = ::std::net::TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nAccept: {}\r\n\r\n", path, accept).unwrap();
+    stream
+  }
+
+  fn read_all(mut stream: ::std::net::TcpStream) -> String {
+    use std::io::Read;
+    let mut buf = Vec::new();
+    let _ = stream.read_to_end(&mut buf);
+    String::from_utf8_lossy(&buf).into_owned()
+  }
+
+  #[test]
+  fn drains_requests_on_shutdown() {
+    use std::time::Instant;
+    let mut server = Server::new();
+    server.resource("/cats", ::Resource::new(::memory::MemoryAdapter::new()).channel(::memory::MemoryChannel::new()));
+    // replies after a while, from another thread
+    server.resource("/slow", |req: Request| {
+      let (tx, rx) = oneshot::channel();
+      thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        let _ = tx.send(req.into_reply(JsonObject::new()));
+      });
+      rx.map_err(|_| Error::new(ErrorKind::ServerError, JsonValue::Null))
+    });
+    server.resource("/stuck", |_: Request| empty::<Reply, Error>());
+    server.shutdown_timeout(Duration::from_millis(800));
+    let addr = StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (tx, rx) = oneshot::channel::<()>();
+    let running = thread::spawn(move || server.listen_until(addr.to_string(), rx.map_err(|_| ())));
+    for _ in 0..500 {
+      if ::std::net::TcpStream::connect(addr).is_ok() {
+        break;
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+
+    let stream = send_raw(addr, "/cats", "text/event-stream");
+    let slow = send_raw(addr, "/slow/1", "application/json");
+    let stuck = send_raw(addr, "/stuck/1", "application/json");
+    thread::sleep(Duration::from_millis(100));
+    let started = Instant::now();
+    tx.send(()).unwrap();
+
+    // the slow request still gets its reply, and the event stream a final `shutdown` event
+    assert!(read_all(slow).starts_with("HTTP/1.1 200"));
+    assert!(read_all(stream).contains("event:shutdown\ndata:{}\n\n"));
+    // the stuck request is given up on once the shutdown timeout runs out
+    assert_eq!(read_all(stuck), "");
+    assert!(running.join().unwrap().is_ok());
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(800) && elapsed < Duration::from_secs(4));
+    assert!(::std::net::TcpStream::connect(addr).is_err());
+  }
+
+  #[test]
+  fn stops_every_worker_when_one_fails() {
+    let mut server = Server::new();
+    server.threads(3);
+    server.resource("/panic", |_: Request| -> FutureResult<Reply, Error> { panic!("handler failed") });
+    let addr = StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (done_tx, done_rx) = ::std::sync::mpsc::channel();
+    thread::spawn(move || done_tx.send(server.listen_until(addr.to_string(), empty())));
+    for _ in 0..500 {
+      if ::std::net::TcpStream::connect(addr).is_ok() {
+        break;
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+    drop(send_raw(addr, "/panic", "application/json"));
+    // the workers that didn't panic stop on their own, instead of waiting for a shutdown
+    match done_rx.recv_timeout(Duration::from_secs(5)) {
+      Ok(Err(ListenError::WorkerPanicked)) => (),
+      res => panic!("expected the server to stop with a panicked worker, got {:?}", res),
+    }
+  }
+
+  #[test]
+  fn reads_last_event_id() {
+    let server = make_server(&["/cats"]);
+    let mut headers = hyper::Headers::new();
+    headers.set_raw("Accept", "text/event-stream");
+    headers.set_raw("Last-Event-ID", "12");
+    let req = http_to_req(&HttpMethod::Get, "/cats", "", &headers, Some(Vec::new()), &server).unwrap();
+    assert_eq!(req.method(), Method::Listen);
+    assert_eq!(req.last_event_id(), Some("12"));
+    // `poll` never ends up in the params, where it would filter the channel's messages
+    let req = http_to_req(&HttpMethod::Get, "/cats", "poll=&color=grey", &headers, Some(Vec::new()), &server).unwrap();
+    assert_eq!(req.method(), Method::Listen);
+    assert_eq!(req.params(), &json!({"color": "grey"}).as_object().unwrap().clone());
+  }
+
+  #[test]
+  fn reads_headers_and_cookies() {
+    let server = make_server(&["/cats"]);
+    let mut headers = hyper::Headers::new();
+    headers.set_raw("Authorization", "Bearer meow");
+    headers.set_raw("Cookie", vec![b"session=abc; theme=\"dark\"".to_vec(), b"lang=en".to_vec()]);
+    let req = http_to_req(&HttpMethod::Get, "/cats", "", &headers, Some(Vec::new()), &server).unwrap();
+    assert_eq!(req.header("authorization"), "Bearer meow");
+    assert_eq!(req.header("AUTHORIZATION"), "Bearer meow");
+    assert_eq!(req.header("x-forwarded-for"), &JsonValue::Null);
+    assert_eq!(req.cookie("session"), "abc");
+    assert_eq!(req.cookie("theme"), "dark");
+    assert_eq!(req.cookie("lang"), "en");
+  }
+
+  #[test]
+  fn routes_actions() {
+    let server = make_server(&["/cats"]);
+    let body = Some(b"{\"amount\": 3}".to_vec());
+    let req = http_to_req(&HttpMethod::Post, "/cats/12/feed", "", &hyper::Headers::new(), body.clone(), &server).unwrap();
+    assert_eq!(req.method(), Method::Action("feed".to_string()));
+    assert_eq!(req.data().get("amount"), Some(&json!(3)));
+    // undeclared collection and `GET` actions aren't routed
+    assert_eq!(http_to_req(&HttpMethod::Post, "/cats/bulk-import", "", &hyper::Headers::new(), body, &server).err().unwrap().status_code(), 405);
+    assert_eq!(to_req(&server, HttpMethod::Get, "/cats/12/stats").err().unwrap().status_code(), 405);
+    let req = to_req(&server, HttpMethod::Get, "/cats/stats").unwrap();
+    assert_eq!(req.method(), Method::Get);
+  }
+
+  #[test]
+  fn rejects_undeclared_actions() {
+    let mut server = Server::new();
+    server.resource("/cats", ::Resource::new(::memory::MemoryAdapter::new())
+      .action(Action::new("stats").collection().get(), |req: Request| req.into_reply(JsonObject::new()))
+      .action(Action::new("feed"), |req: Request| req.into_reply(JsonObject::new()))
+      .action(Action::new("import").collection(), |req: Request| req.into_reply(JsonObject::new())));
+    let server = Arc::new(server);
+    let req = to_req(&server, HttpMethod::Get, "/cats/stats").unwrap();
+    assert_eq!(req.method(), Method::Action("stats".to_string()));
+    assert_eq!(req.id(), &None);
+    assert!(server.handle(req).wait().is_ok());
+    assert!(to_req(&server, HttpMethod::Post, "/cats/12/feed").is_ok());
+    let req = to_req(&server, HttpMethod::Post, "/cats/import").unwrap();
+    assert_eq!(req.method(), Method::Action("import".to_string()));
+    assert_eq!(to_req(&server, HttpMethod::Get, "/cats/12/feed").err().unwrap().status_code(), 405);
+    assert_eq!(to_req(&server, HttpMethod::Post, "/cats/stats").err().unwrap().status_code(), 405);
+    assert_eq!(to_req(&server, HttpMethod::Post, "/cats/12/pet").err().unwrap().status_code(), 405);
+  }
+
+  fn tracked_body(body: Body, options: StreamOptions, handle: &Handle) -> TrackedBody {
+    let shutdown: BoxFuture<(), ()> = empty().boxed();
+    TrackedBody {
+      body: body,
+      shutdown: shutdown.shared(),
+      done: false,
+      _in_flight: InFlight::new(&Arc::new(AtomicUsize::new(0))),
+      retry: options.retry.map(|d| format!("retry:{}\n\n", millis(d)).into()),
+      heartbeat: heartbeat_interval(options, handle),
+    }
+  }
+
+  fn chunks(core: &mut Core, body: TrackedBody, count: u64) -> Vec<String> {
+    let chunks = core.run(body.take(count).collect()).unwrap();
+    chunks.iter().map(|c| String::from_utf8(c.to_vec()).unwrap()).collect()
+  }
+
+  #[test]
+  fn sends_retry_and_heartbeats() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let server = StreamOptions {
+      heartbeat: Some(Duration::from_millis(20)),
+      retry: Some(Duration::from_millis(1500)),
+    };
+    let options = merge_options(StreamOptions::default(), server);
+    let body = tracked_body(Body::Stream(empty().into_stream().boxed()), options, &handle);
+    assert_eq!(chunks(&mut core, body, 3), vec!["retry:1500\n\n", ":keepalive\n\n", ":keepalive\n\n"]);
+
+    // a zero heartbeat turns off the server's heartbeats, so nothing gets sent between the events
+    let disabled = StreamOptions { heartbeat: Some(Duration::new(0, 0)), retry: None };
+    let options = merge_options(disabled, server);
+    assert_eq!(options.heartbeat, Some(Duration::new(0, 0)));
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+      thread::sleep(Duration::from_millis(100));
+      tx.send("data:2\n\n".into()).unwrap();
+    });
+    let events = futures::stream::iter_ok(vec!["data:1\n\n".into()]).chain(rx.map_err(|_| ()).into_stream());
+    let body = tracked_body(Body::Stream(events.boxed()), options, &handle);
+    assert_eq!(chunks(&mut core, body, 4), vec!["retry:1500\n\n", "data:1\n\n", "data:2\n\n"]);
   }
 }
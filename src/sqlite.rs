@@ -0,0 +1,299 @@
+/*!
+A SQLite database adapter, enabled with the `sqlite` feature.
+*/
+
+use {JsonValue, JsonObject, ErrorKind, Adapter};
+use query::{Query, ListOptions};
+use patch;
+use futures::future::{Future, BoxFuture, err};
+use futures_cpupool::CpuPool;
+use rusqlite::{self, Connection, OptionalExtension};
+use serde_json;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+fn std_error(kind: ErrorKind, err_str: &str) -> (ErrorKind, JsonValue) {
+  let val = json!({
+    "error": {
+      "type": kind.as_string(),
+      "message": err_str.to_string(),
+    }
+  });
+  (kind, val)
+}
+
+fn db_error(_: rusqlite::Error) -> (ErrorKind, JsonValue) {
+  std_error(ErrorKind::ServerError, "database error")
+}
+
+fn not_found() -> (ErrorKind, JsonValue) {
+  std_error(ErrorKind::NotFound, "couldn't find object with that id")
+}
+
+type DbResult = Result<JsonObject, (ErrorKind, JsonValue)>;
+
+/**
+Stores each resource as a table of JSON documents in a SQLite database.
+
+Each table has an integer `id` column and a `data` column containing the rest of the object as
+JSON. Queries run on a separate thread, so they don't block the event loop. `list` supports the
+same filtering, sorting and pagination as `MemoryAdapter`, by loading the table and filtering it
+with the `query` module, so it's best suited to tables that fit in memory.
+
+```rust,no_run
+# extern crate backtalk;
+# use backtalk::*;
+# fn main() {
+let mut server = Server::new();
+let database = sqlite::SqliteAdapter::open("cats.db", "cats").unwrap();
+server.resource("/cats", move |req: Request| {
+  database.handle(req)
+});
+# }
+```
+*/
+pub struct SqliteAdapter {
+  conn: Arc<Mutex<Connection>>,
+  // quoted, so names like `order` that are SQL keywords work too
+  table: String,
+  pool: CpuPool,
+}
+
+// the table name is put into the SQL in double quotes, so it can't have quotes of its own
+fn check_table_name(table: &str) -> Result<(), rusqlite::Error> {
+  if table != "" && table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+    Ok(())
+  } else {
+    let code = rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_MISUSE);
+    Err(rusqlite::Error::SqliteFailure(code, Some(format!("invalid SQLite table name {:?}", table))))
+  }
+}
+
+impl SqliteAdapter {
+  /**
+  Opens the database file at `path`, creating it and the table if needed. Several adapters can
+  open the same file with different tables.
+
+  Returns an error if `table` contains characters other than ASCII letters, numbers and
+  underscores.
+  */
+  pub fn open<P: AsRef<Path>>(path: P, table: &str) -> Result<SqliteAdapter, rusqlite::Error> {
+    check_table_name(table)?;
+    SqliteAdapter::from_connection(Connection::open(path)?, table)
+  }
+
+  /// Creates an adapter for a new, empty database in memory, which is handy for tests.
+  pub fn open_in_memory(table: &str) -> Result<SqliteAdapter, rusqlite::Error> {
+    check_table_name(table)?;
+    SqliteAdapter::from_connection(Connection::open_in_memory()?, table)
+  }
+
+  fn from_connection(conn: Connection, table: &str) -> Result<SqliteAdapter, rusqlite::Error> {
+    let table = format!("\"{}\"", table);
+    conn.execute(&format!("CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY AUTOINCREMENT, data TEXT NOT NULL)", table), rusqlite::NO_PARAMS)?;
+    Ok(SqliteAdapter {
+      conn: Arc::new(Mutex::new(conn)),
+      table: table,
+      pool: CpuPool::new(1),
+    })
+  }
+
+  // runs a query on the pool's thread instead of the event loop
+  fn run<F>(&self, f: F) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)>
+    where F: FnOnce(&mut Connection, &str) -> DbResult + Send + 'static
+  {
+    let conn = self.conn.clone();
+    let table = self.table.clone();
+    self.pool.spawn_fn(move || {
+      let mut conn = conn.lock().unwrap();
+      f(&mut conn, &table)
+    }).boxed()
+  }
+}
+
+fn parse_id(id: &str) -> Option<i64> {
+  id.parse().ok()
+}
+
+fn to_object(id: i64, data: &str) -> DbResult {
+  let mut obj: JsonObject = serde_json::from_str(data)
+    .map_err(|_| std_error(ErrorKind::ServerError, "invalid JSON in database"))?;
+  obj.insert("id".to_string(), JsonValue::String(id.to_string()));
+  Ok(obj)
+}
+
+fn to_data(obj: &JsonObject) -> String {
+  let mut obj = obj.clone();
+  obj.remove("id");
+  JsonValue::Object(obj).to_string()
+}
+
+fn select(conn: &Connection, table: &str, id: i64) -> Result<Option<JsonObject>, (ErrorKind, JsonValue)> {
+  let data: Option<String> = conn.query_row(
+    &format!("SELECT data FROM {} WHERE id = ?", table),
+    &[&id],
+    |row| row.get(0)
+  ).optional().map_err(db_error)?;
+  match data {
+    Some(data) => to_object(id, &data).map(Some),
+    None => Ok(None),
+  }
+}
+
+impl Adapter for SqliteAdapter {
+  fn list(&self, params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let (query, options) = match (Query::parse(params), ListOptions::parse(params)) {
+      (Ok(q), Ok(o)) => (q, o),
+      (Err(e), _) | (_, Err(e)) => return err(e).boxed(),
+    };
+    self.run(move |conn, table| {
+      let mut stmt = conn.prepare(&format!("SELECT id, data FROM {}", table)).map_err(db_error)?;
+      let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?))).map_err(db_error)?;
+      let mut items = Vec::new();
+      for row in rows {
+        let (id, data): (i64, String) = row.map_err(db_error)?;
+        let item = to_object(id, &data)?;
+        if query.matches(&item) {
+          items.push(item);
+        }
+      }
+      Ok(options.paginate(items).to_object())
+    })
+  }
+
+  fn get(&self, id: &str, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let id = match parse_id(id) {
+      Some(id) => id,
+      None => return err(not_found()).boxed(),
+    };
+    self.run(move |conn, table| {
+      select(conn, table, id)?.ok_or_else(not_found)
+    })
+  }
+
+  fn post(&self, data: &JsonObject, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let data = data.clone();
+    self.run(move |conn, table| {
+      conn.execute(&format!("INSERT INTO {} (data) VALUES (?)", table), &[&to_data(&data)]).map_err(db_error)?;
+      let mut data = data;
+      data.insert("id".to_string(), JsonValue::String(conn.last_insert_rowid().to_string()));
+      Ok(data)
+    })
+  }
+
+  fn patch(&self, id: &str, data: &JsonObject, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    if let Some(_) = data.get("id") {
+      return err(std_error(ErrorKind::BadRequest, "can't update id")).boxed();
+    }
+    let id = match parse_id(id) {
+      Some(id) => id,
+      None => return err(not_found()).boxed(),
+    };
+    let data = data.clone();
+    self.run(move |conn, table| {
+      let tx = conn.transaction().map_err(db_error)?;
+      let mut dbdata = select(&tx, table, id)?.ok_or_else(not_found)?;
+      patch::merge(&mut dbdata, &data);
+      tx.execute(&format!("UPDATE {} SET data = ? WHERE id = ?", table), &[&to_data(&dbdata) as &rusqlite::ToSql, &id]).map_err(db_error)?;
+      tx.commit().map_err(db_error)?;
+      Ok(dbdata)
+    })
+  }
+
+  fn json_patch(&self, id: &str, ops: &[JsonValue], _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let id = match parse_id(id) {
+      Some(id) => id,
+      None => return err(not_found()).boxed(),
+    };
+    let ops = ops.to_vec();
+    self.run(move |conn, table| {
+      let tx = conn.transaction().map_err(db_error)?;
+      let dbdata = patch::apply(&select(&tx, table, id)?.ok_or_else(not_found)?, &ops)?;
+      if dbdata.get("id") != Some(&JsonValue::String(id.to_string())) {
+        return Err(std_error(ErrorKind::BadRequest, "can't update id"));
+      }
+      tx.execute(&format!("UPDATE {} SET data = ? WHERE id = ?", table), &[&to_data(&dbdata) as &rusqlite::ToSql, &id]).map_err(db_error)?;
+      tx.commit().map_err(db_error)?;
+      Ok(dbdata)
+    })
+  }
+
+  /// ids are integers, so replacing an object at a non-integer id is a `BadRequest`
+  fn update(&self, id: &str, data: &JsonObject, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    match data.get("id") {
+      Some(&JsonValue::String(ref data_id)) if data_id == id => (),
+      Some(_) => return err(std_error(ErrorKind::BadRequest, "id in data doesn't match the URL")).boxed(),
+      None => (),
+    }
+    let id = match parse_id(id) {
+      Some(id) => id,
+      None => return err(std_error(ErrorKind::BadRequest, "ids in this table are integers")).boxed(),
+    };
+    let data = data.clone();
+    self.run(move |conn, table| {
+      conn.execute(&format!("INSERT OR REPLACE INTO {} (id, data) VALUES (?, ?)", table), &[&id as &rusqlite::ToSql, &to_data(&data)]).map_err(db_error)?;
+      let mut data = data;
+      data.insert("id".to_string(), JsonValue::String(id.to_string()));
+      Ok(data)
+    })
+  }
+
+  fn delete(&self, id: &str, _params: &JsonObject) -> BoxFuture<JsonObject, (ErrorKind, JsonValue)> {
+    let id_str = id.to_string();
+    self.run(move |conn, table| {
+      if let Some(id) = parse_id(&id_str) {
+        conn.execute(&format!("DELETE FROM {} WHERE id = ?", table), &[&id]).map_err(db_error)?;
+      }
+      let mut data = JsonObject::new();
+      data.insert("id".to_string(), JsonValue::String(id_str));
+      Ok(data)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use obj;
+
+  #[test]
+  fn stores_and_queries_documents() {
+    let adapter = SqliteAdapter::open_in_memory("cats").unwrap();
+    let tom = adapter.post(&obj(json!({"name": "Tom", "age": 3})), &JsonObject::new()).wait().unwrap();
+    adapter.post(&obj(json!({"name": "Sam", "age": 5})), &JsonObject::new()).wait().unwrap();
+    assert_eq!(tom.get("id").unwrap(), "1");
+    let tom = adapter.patch("1", &obj(json!({"age": 4})), &JsonObject::new()).wait().unwrap();
+    assert_eq!(tom.get("name").unwrap(), "Tom");
+    assert_eq!(adapter.get("1", &JsonObject::new()).wait().unwrap().get("age").unwrap(), 4);
+
+    let page = adapter.list(&obj(json!({"age": {"$gt": "3"}, "$sort": "-age"}))).wait().unwrap();
+    assert_eq!(page.get("total").unwrap(), 2);
+    assert_eq!(page.get("data").unwrap()[0].get("name").unwrap(), "Sam");
+
+    let felix = adapter.update("10", &obj(json!({"name": "Felix"})), &JsonObject::new()).wait().unwrap();
+    assert_eq!(felix.get("id").unwrap(), "10");
+    adapter.update("10", &obj(json!({"name": "Felix", "age": 2})), &JsonObject::new()).wait().unwrap();
+    assert_eq!(adapter.get("10", &JsonObject::new()).wait().unwrap().get("age").unwrap(), 2);
+    assert!(adapter.update("felix", &JsonObject::new(), &JsonObject::new()).wait().is_err());
+    assert_eq!(adapter.post(&JsonObject::new(), &JsonObject::new()).wait().unwrap().get("id").unwrap(), "11");
+
+    adapter.delete("1", &JsonObject::new()).wait().unwrap();
+    assert!(adapter.get("1", &JsonObject::new()).wait().is_err());
+    assert!(adapter.get("not-a-number", &JsonObject::new()).wait().is_err());
+  }
+
+  #[test]
+  fn checks_table_names() {
+    assert!(SqliteAdapter::open_in_memory("").is_err());
+    assert!(SqliteAdapter::open_in_memory("cats; DROP TABLE dogs").is_err());
+    assert!(SqliteAdapter::open_in_memory("big_cats2").is_ok());
+    assert!(SqliteAdapter::open_in_memory("cats\"").is_err());
+    // keywords and names starting with a digit work, since they're quoted
+    for table in &["order", "1abc"] {
+      let adapter = SqliteAdapter::open_in_memory(table).unwrap();
+      adapter.post(&obj(json!({"name": "Tom"})), &JsonObject::new()).wait().unwrap();
+      assert_eq!(adapter.get("1", &JsonObject::new()).wait().unwrap().get("name").unwrap(), "Tom");
+      assert_eq!(adapter.list(&JsonObject::new()).wait().unwrap().get("total").unwrap(), 1);
+    }
+  }
+}
@@ -0,0 +1,296 @@
+use {JsonValue, JsonObject, Error, ErrorKind};
+use serde::de::{self, Deserialize, Deserializer, Visitor, SeqVisitor, MapVisitor, DeserializeSeed};
+use serde::Serialize;
+use serde_json;
+use std::{error, fmt};
+use std::vec;
+
+// a deserialization error, and where in the data it happened
+#[derive(Debug)]
+struct FieldError {
+  field: Option<String>,
+  message: String,
+}
+
+impl FieldError {
+  // errors from deeper in the data already know their field, so this only sets it the first time
+  fn at(mut self, field: &str) -> FieldError {
+    if self.field.is_none() && field != "" {
+      self.field = Some(field.to_string());
+    }
+    self
+  }
+}
+
+impl fmt::Display for FieldError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.field {
+      Some(ref field) => write!(f, "{} at `{}`", self.message, field),
+      None => write!(f, "{}", self.message),
+    }
+  }
+}
+
+impl error::Error for FieldError {
+  fn description(&self) -> &str {
+    &self.message
+  }
+}
+
+impl de::Error for FieldError {
+  fn custom<T: fmt::Display>(msg: T) -> FieldError {
+    FieldError {
+      field: None,
+      message: msg.to_string(),
+    }
+  }
+}
+
+impl From<serde_json::Error> for FieldError {
+  fn from(e: serde_json::Error) -> FieldError {
+    de::Error::custom(e)
+  }
+}
+
+// deserializes a JSON value, keeping track of the path to it so errors can say which field was bad
+struct FieldDeserializer {
+  value: JsonValue,
+  path: String,
+  // params come from the query string, so they're all strings, but `"5"` should still work as a number
+  lenient: bool,
+}
+
+impl FieldDeserializer {
+  fn child(&self, value: JsonValue, key: &str) -> FieldDeserializer {
+    FieldDeserializer {
+      value: value,
+      path: if self.path == "" { key.to_string() } else { format!("{}.{}", self.path, key) },
+      lenient: self.lenient,
+    }
+  }
+
+  // for lenient deserializers, turns strings like `"5"` or `"true"` into numbers and bools
+  fn parsed(mut self) -> FieldDeserializer {
+    let parsed = match self.value {
+      JsonValue::String(ref s) if self.lenient => match serde_json::from_str(s) {
+        Ok(val @ JsonValue::Number(_)) | Ok(val @ JsonValue::Bool(_)) => Some(val),
+        _ => None,
+      },
+      _ => None,
+    };
+    if let Some(val) = parsed {
+      self.value = val;
+    }
+    self
+  }
+}
+
+macro_rules! parse_then_deserialize {
+  ($($method:ident)*) => {
+    $(
+      fn $method<V: Visitor>(self, visitor: V) -> Result<V::Value, FieldError> {
+        self.parsed().deserialize(visitor)
+      }
+    )*
+  };
+}
+
+impl Deserializer for FieldDeserializer {
+  type Error = FieldError;
+
+  fn deserialize<V: Visitor>(self, visitor: V) -> Result<V::Value, FieldError> {
+    let path = self.path.clone();
+    let res = match self.value {
+      JsonValue::Array(items) => {
+        let len = items.len();
+        visitor.visit_seq(FieldSeq {
+          parent: FieldDeserializer { value: JsonValue::Null, path: self.path, lenient: self.lenient },
+          items: items.into_iter(),
+          index: 0,
+          len: len,
+        })
+      },
+      JsonValue::Object(obj) => {
+        visitor.visit_map(FieldMap {
+          parent: FieldDeserializer { value: JsonValue::Null, path: self.path, lenient: self.lenient },
+          entries: obj.into_iter().collect::<Vec<_>>().into_iter(),
+          value: None,
+        })
+      },
+      value => value.deserialize(visitor).map_err(FieldError::from),
+    };
+    res.map_err(|e| e.at(&path))
+  }
+
+  fn deserialize_option<V: Visitor>(self, visitor: V) -> Result<V::Value, FieldError> {
+    let path = self.path.clone();
+    match self.value {
+      JsonValue::Null => visitor.visit_none(),
+      _ => visitor.visit_some(self),
+    }.map_err(|e| e.at(&path))
+  }
+
+  fn deserialize_newtype_struct<V: Visitor>(self, _name: &'static str, visitor: V) -> Result<V::Value, FieldError> {
+    let path = self.path.clone();
+    visitor.visit_newtype_struct(self).map_err(|e| e.at(&path))
+  }
+
+  fn deserialize_enum<V: Visitor>(self, name: &'static str, variants: &'static [&'static str], visitor: V) -> Result<V::Value, FieldError> {
+    let path = self.path.clone();
+    self.value.deserialize_enum(name, variants, visitor).map_err(|e| FieldError::from(e).at(&path))
+  }
+
+  parse_then_deserialize! {
+    deserialize_bool deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_i8
+    deserialize_i16 deserialize_i32 deserialize_i64 deserialize_f32 deserialize_f64
+  }
+
+  forward_to_deserialize! {
+    char str string unit seq seq_fixed_size bytes byte_buf map unit_struct tuple_struct struct
+    struct_field tuple ignored_any
+  }
+}
+
+struct FieldSeq {
+  parent: FieldDeserializer,
+  items: vec::IntoIter<JsonValue>,
+  index: usize,
+  len: usize,
+}
+
+impl SeqVisitor for FieldSeq {
+  type Error = FieldError;
+
+  fn visit_seed<T: DeserializeSeed>(&mut self, seed: T) -> Result<Option<T::Value>, FieldError> {
+    match self.items.next() {
+      Some(item) => {
+        let child = self.parent.child(item, &self.index.to_string());
+        self.index += 1;
+        seed.deserialize(child).map(Some)
+      },
+      None => Ok(None),
+    }
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    let left = self.len - self.index;
+    (left, Some(left))
+  }
+}
+
+struct FieldMap {
+  parent: FieldDeserializer,
+  entries: vec::IntoIter<(String, JsonValue)>,
+  // the value for the key that was just visited
+  value: Option<(String, JsonValue)>,
+}
+
+impl MapVisitor for FieldMap {
+  type Error = FieldError;
+
+  fn visit_key_seed<K: DeserializeSeed>(&mut self, seed: K) -> Result<Option<K::Value>, FieldError> {
+    match self.entries.next() {
+      Some((key, value)) => {
+        let res = seed.deserialize(JsonValue::String(key.clone())).map_err(FieldError::from);
+        self.value = Some((key, value));
+        res.map(Some)
+      },
+      None => Ok(None),
+    }
+  }
+
+  fn visit_value_seed<V: DeserializeSeed>(&mut self, seed: V) -> Result<V::Value, FieldError> {
+    match self.value.take() {
+      Some((key, value)) => seed.deserialize(self.parent.child(value, &key)),
+      None => Err(de::Error::custom("value visited before its key")),
+    }
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    self.entries.size_hint()
+  }
+}
+
+fn bad_request(e: FieldError) -> Error {
+  let mut err = JsonObject::new();
+  err.insert("type".to_string(), JsonValue::String(ErrorKind::BadRequest.as_string()));
+  err.insert("message".to_string(), JsonValue::String(e.to_string()));
+  if let Some(field) = e.field {
+    err.insert("field".to_string(), JsonValue::String(field));
+  }
+  let mut val = JsonObject::new();
+  val.insert("error".to_string(), JsonValue::Object(err));
+  Error::new(ErrorKind::BadRequest, JsonValue::Object(val))
+}
+
+// only used internally, deserializes request data or params into `T`, with a `BadRequest` error
+// naming the bad field if it doesn't fit. `lenient` lets strings stand in for numbers and bools.
+pub fn from_object<T: Deserialize>(obj: &JsonObject, lenient: bool) -> Result<T, Error> {
+  T::deserialize(FieldDeserializer {
+    value: JsonValue::Object(obj.clone()),
+    path: String::new(),
+    lenient: lenient,
+  }).map_err(bad_request)
+}
+
+// only used internally, serializes reply data, which has to be a JSON object
+pub fn to_object<T: Serialize>(val: T) -> Result<JsonObject, Error> {
+  let message = match serde_json::to_value(val) {
+    Ok(JsonValue::Object(obj)) => return Ok(obj),
+    Ok(_) => "reply data has to serialize to a JSON object".to_string(),
+    Err(e) => format!("couldn't serialize reply data: {}", e),
+  };
+  Err(Error::new(ErrorKind::ServerError, json!({
+    "error": {
+      "type": ErrorKind::ServerError.as_string(),
+      "message": message,
+    }
+  })))
+}
+
+#[cfg(test)]
+mod tests {
+  use {Request, Method};
+  use super::*;
+  use std::collections::HashMap;
+
+  fn make_req(data: JsonValue, params: JsonValue) -> Request {
+    match (data, params) {
+      (JsonValue::Object(data), JsonValue::Object(params)) => Request::new("/cats".to_string(), Method::Post, None, data, params),
+      _ => panic!("not objects"),
+    }
+  }
+
+  fn field(err: Error) -> JsonValue {
+    err.data()["error"]["field"].clone()
+  }
+
+  #[test]
+  fn deserializes_with_field_errors() {
+    let req = make_req(json!({"ages": {"tom": 3, "jerry": "old"}, "tags": ["grey", 5]}), json!({"limit": "10"}));
+    let params: HashMap<String, u32> = req.params_as().unwrap();
+    assert_eq!(params.get("limit"), Some(&10));
+    assert!(req.data_as::<HashMap<String, u32>>().is_err());
+
+    let err = req.data_as::<HashMap<String, HashMap<String, u32>>>().err().unwrap();
+    assert_eq!(err.status_code(), 400);
+    assert_eq!(field(err), json!("ages.jerry"));
+    let err = req.data_as::<(HashMap<String, JsonValue>,)>().err().unwrap();
+    assert_eq!(field(err), JsonValue::Null);
+    let mut data = req.data().clone();
+    data.remove("ages");
+    let req = make_req(JsonValue::Object(data), json!({}));
+    let err = req.data_as::<HashMap<String, Vec<String>>>().err().unwrap();
+    assert_eq!(field(err), json!("tags.1"));
+  }
+
+  #[test]
+  fn serializes_replies() {
+    let mut data = HashMap::new();
+    data.insert("name", "Tom");
+    let reply = make_req(json!({}), json!({})).into_reply_from(data).unwrap();
+    assert_eq!(reply.data().unwrap().get("name"), Some(&json!("Tom")));
+    let err = make_req(json!({}), json!({})).into_reply_from(vec![1, 2]).err().unwrap();
+    assert_eq!(err.status_code(), 500);
+  }
+}
@@ -1,7 +1,27 @@
-use {Channel, Reply, Method};
-pub fn send_from_reply<C: Channel>(reply: Reply, chan: &C) -> Reply {
+use {Channel, Reply, Method, JsonValue};
+
+// sends the reply data to the clients listening to the reply's id, or to everyone if it has no id
+fn send_about_reply<C: Channel + ?Sized>(reply: &Reply, event: &str, id_field: &str, chan: &C) {
+    if let Some(data) = reply.data() {
+        // new objects only have an id in the reply data
+        let id = reply.id().clone().or_else(|| match data.get(id_field) {
+            Some(&JsonValue::String(ref id)) => Some(id.clone()),
+            _ => None,
+        });
+        match id {
+            Some(id) => chan.send_about(&id, event, data),
+            None => chan.send(event, data),
+        }
+    }
+}
+
+/**
+Sends a successful `Post`, `Patch`, `Put`, `Delete` or `Action` reply to every client listening to
+the channel, with the method name as the event type, like `"post"`.
+*/
+pub fn send_from_reply<C: Channel + ?Sized>(reply: Reply, chan: &C) -> Reply {
     match reply.method() {
-        Method::Delete | Method::Post | Method::Patch | Method::Action(_) => {
+        Method::Delete | Method::Post | Method::Patch | Method::Put | Method::Action(_) => {
             match reply.data() {
                 Some(data) => chan.send(&reply.method().as_string(), data),
                 None => (),
@@ -12,3 +32,29 @@ pub fn send_from_reply<C: Channel>(reply: Reply, chan: &C) -> Reply {
 
     reply
 }
+
+/**
+Sends a successful `Post`, `Patch`, `Put`, `Delete` or `Action` reply to the channel, as a
+`"created"`, `"patched"`, `"updated"`, `"removed"` or action-named event about the reply's id.
+Unlike `send_from_reply`, clients listening to other ids don't get the event.
+*/
+pub fn publish_reply<C: Channel + ?Sized>(reply: Reply, chan: &C) -> Reply {
+    publish_reply_with_id_field(reply, "id", chan)
+}
+
+/**
+Like `publish_reply`, for objects that keep their id in `id_field` instead of `"id"`, like an
+adapter's `Adapter::id_field`.
+*/
+pub fn publish_reply_with_id_field<C: Channel + ?Sized>(reply: Reply, id_field: &str, chan: &C) -> Reply {
+    let event = match reply.method() {
+        Method::Post => "created".to_string(),
+        Method::Patch => "patched".to_string(),
+        Method::Put => "updated".to_string(),
+        Method::Delete => "removed".to_string(),
+        Method::Action(name) => name,
+        _ => return reply,
+    };
+    send_about_reply(&reply, &event, id_field, chan);
+    reply
+}
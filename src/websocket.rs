@@ -0,0 +1,565 @@
+/*!
+A WebSocket transport for `Listen` requests, on the same URLs as the event-stream transport.
+
+A client upgrading `GET /cats/123` to a WebSocket gets the same streaming `Reply` an
+`Accept: text/event-stream` request would, with each event sent as a text message like
+`{"event": "post", "data": {...}}`, plus an `"id"` if the channel sent the event with one.
+
+Clients can also send requests over the socket as text messages, like:
+
+```json
+{"ref": 1, "method": "POST", "path": "/cats?foo=bar", "data": {"name": "Fluffy"}}
+```
+
+These are routed through the `Server` as if they were normal HTTP requests, with the headers,
+cookies and remote address of the request that opened the socket, and the server answers
+with `{"ref": 1, "status": 200, "data": {...}}`, where `ref` is copied from the request so clients
+can match up replies with requests. Errors are sent the same way, with the error's status code
+and JSON data. Each socket handles up to 16 requests at a time, and stops reading new messages until
+one of them is answered and the reply is on its way to the client.
+
+Sockets that break the protocol, for instance with fragmented or oversized control frames, are
+closed with status code 1002.
+*/
+
+use {JsonValue, JsonObject, Error, ErrorKind, Server};
+use server::{http_to_req, read_headers};
+use reply::EventReceiver;
+use std::{error as std_err, fmt, io};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use bytes::{Bytes, BytesMut, BufMut};
+use futures::{Future, Sink, Stream};
+use futures::future::{ok, BoxFuture};
+use futures::stream;
+use futures::sync::mpsc;
+use hyper::{Headers, StatusCode};
+use hyper::Method as HttpMethod;
+use hyper::server as http;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_codec::{Decoder, Encoder, Framed, FramedParts};
+use serde_json;
+use sha1::Sha1;
+use base64;
+
+const ACCEPT_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+// the most requests a socket handles at once, and the most replies waiting to be written to it
+const MAX_IN_FLIGHT: usize = 16;
+// close status codes, from RFC 6455 section 7.4.1
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+const CLOSE_SERVER_ERROR: u16 = 1011;
+
+fn std_error(kind: ErrorKind, err_str: &str) -> Error {
+  let val = json!({
+    "error": {
+      "type": kind.as_string(),
+      "message": err_str.to_string(),
+    }
+  });
+  Error::new(
+    kind,
+    val
+  )
+}
+
+fn header_has_token(headers: &Headers, name: &str, token: &str) -> bool {
+  match headers.get_raw(name) {
+    Some(raw) => raw.iter().any(|line| {
+      String::from_utf8_lossy(line)
+        .split(",")
+        .any(|t| t.trim().to_lowercase() == token)
+    }),
+    None => false,
+  }
+}
+
+// only used internally, returns the `Sec-WebSocket-Key` if the request wants to upgrade
+pub fn upgrade_key(method: &HttpMethod, headers: &Headers) -> Option<String> {
+  if method != &HttpMethod::Get ||
+     !header_has_token(headers, "Connection", "upgrade") ||
+     !header_has_token(headers, "Upgrade", "websocket") ||
+     !header_has_token(headers, "Sec-WebSocket-Version", "13") {
+    return None;
+  }
+  headers.get_raw("Sec-WebSocket-Key")
+    .and_then(|raw| raw.one())
+    .and_then(|key| String::from_utf8(key.to_vec()).ok())
+    .map(|key| key.trim().to_string())
+}
+
+// only used internally
+pub fn handshake_response<B>(key: &str) -> http::Response<B> {
+  let digest = Sha1::from(format!("{}{}", key, ACCEPT_GUID)).digest().bytes();
+  let mut resp = http::Response::new()
+    .with_status(StatusCode::SwitchingProtocols);
+  resp.headers_mut().set_raw("Upgrade", "websocket");
+  resp.headers_mut().set_raw("Connection", "Upgrade");
+  resp.headers_mut().set_raw("Sec-WebSocket-Accept", base64::encode(&digest));
+  resp
+}
+
+#[derive(Debug, PartialEq)]
+enum Message {
+  Text(String),
+  Binary(Vec<u8>),
+  Ping(Vec<u8>),
+  Pong(Vec<u8>),
+  Close(Option<u16>),
+}
+
+impl Message {
+  fn is_close(&self) -> bool {
+    match self {
+      &Message::Close(_) => true,
+      _ => false,
+    }
+  }
+}
+
+// a client breaking the websocket protocol, and the status code to close the socket with
+#[derive(Debug)]
+struct ProtocolError {
+  code: u16,
+  message: &'static str,
+}
+
+impl fmt::Display for ProtocolError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.message)
+  }
+}
+
+impl std_err::Error for ProtocolError {
+  fn description(&self) -> &str {
+    self.message
+  }
+}
+
+fn invalid_data(code: u16, message: &'static str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, ProtocolError { code: code, message: message })
+}
+
+// the status code to close the socket with after a read fails
+fn close_code(e: &io::Error) -> u16 {
+  e.get_ref()
+    .and_then(|inner| inner.downcast_ref::<ProtocolError>())
+    .map(|e| e.code)
+    .unwrap_or(CLOSE_SERVER_ERROR)
+}
+
+/// Reads masked frames from clients and writes unmasked frames, joining fragmented messages.
+struct Codec {
+  fragments: Option<(u8, Vec<u8>)>,
+}
+
+impl Codec {
+  fn new() -> Codec {
+    Codec {
+      fragments: None,
+    }
+  }
+}
+
+fn to_message(opcode: u8, data: Vec<u8>) -> io::Result<Message> {
+  if opcode == 0x1 {
+    String::from_utf8(data)
+      .map(Message::Text)
+      .map_err(|_| invalid_data(CLOSE_INVALID_DATA, "invalid unicode in websocket text message"))
+  } else {
+    Ok(Message::Binary(data))
+  }
+}
+
+impl Decoder for Codec {
+  type Item = Message;
+  type Error = io::Error;
+
+  fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Message>> {
+    loop {
+      if buf.len() < 2 {
+        return Ok(None);
+      }
+      let fin = buf[0] & 0x80 != 0;
+      let opcode = buf[0] & 0x0f;
+      if buf[1] & 0x80 == 0 {
+        return Err(invalid_data(CLOSE_PROTOCOL_ERROR, "websocket frames from clients must be masked"));
+      }
+      let (len, header_len) = match buf[1] & 0x7f {
+        126 => {
+          if buf.len() < 4 {
+            return Ok(None);
+          }
+          (((buf[2] as u64) << 8) | buf[3] as u64, 4)
+        },
+        127 => {
+          if buf.len() < 10 {
+            return Ok(None);
+          }
+          (buf[2..10].iter().fold(0, |len, b| (len << 8) | *b as u64), 10)
+        },
+        n => (n as u64, 2),
+      };
+      if opcode >= 0x8 && (!fin || len > 125) {
+        return Err(invalid_data(CLOSE_PROTOCOL_ERROR, "websocket control frames can't be fragmented or longer than 125 bytes"));
+      }
+      if len > MAX_MESSAGE_SIZE as u64 {
+        return Err(invalid_data(CLOSE_TOO_BIG, "websocket message too large"));
+      }
+      let len = len as usize;
+      if buf.len() < header_len + 4 + len {
+        return Ok(None);
+      }
+      let frame = buf.split_to(header_len + 4 + len);
+      let mask = &frame[header_len..header_len + 4];
+      let payload: Vec<u8> = frame[header_len + 4..]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+
+      match opcode {
+        0x0 => {
+          let (first_opcode, mut data) = match self.fragments.take() {
+            Some(t) => t,
+            None => return Err(invalid_data(CLOSE_PROTOCOL_ERROR, "unexpected websocket continuation frame")),
+          };
+          data.extend_from_slice(&payload);
+          if data.len() > MAX_MESSAGE_SIZE {
+            return Err(invalid_data(CLOSE_TOO_BIG, "websocket message too large"));
+          }
+          if fin {
+            return to_message(first_opcode, data).map(Some);
+          }
+          self.fragments = Some((first_opcode, data));
+        },
+        0x1 | 0x2 => {
+          if self.fragments.is_some() {
+            return Err(invalid_data(CLOSE_PROTOCOL_ERROR, "expected websocket continuation frame"));
+          }
+          if fin {
+            return to_message(opcode, payload).map(Some);
+          }
+          self.fragments = Some((opcode, payload));
+        },
+        0x8 => {
+          let code = match payload.len() {
+            0 => None,
+            _ if payload.len() >= 2 => Some(((payload[0] as u16) << 8) | payload[1] as u16),
+            _ => return Err(invalid_data(CLOSE_PROTOCOL_ERROR, "invalid websocket close frame")),
+          };
+          return Ok(Some(Message::Close(code)));
+        },
+        0x9 => return Ok(Some(Message::Ping(payload))),
+        0xA => return Ok(Some(Message::Pong(payload))),
+        _ => return Err(invalid_data(CLOSE_PROTOCOL_ERROR, "unknown websocket opcode")),
+      }
+    }
+  }
+}
+
+impl Encoder for Codec {
+  type Item = Message;
+  type Error = io::Error;
+
+  fn encode(&mut self, msg: Message, buf: &mut BytesMut) -> io::Result<()> {
+    let (opcode, payload) = match msg {
+      Message::Text(s) => (0x1, s.into_bytes()),
+      Message::Binary(b) => (0x2, b),
+      Message::Close(None) => (0x8, Vec::new()),
+      Message::Close(Some(code)) => (0x8, vec![(code >> 8) as u8, code as u8]),
+      Message::Ping(b) => (0x9, b),
+      Message::Pong(b) => (0xA, b),
+    };
+    let len = payload.len();
+    buf.reserve(len + 10);
+    buf.put_u8(0x80 | opcode);
+    if len < 126 {
+      buf.put_u8(len as u8);
+    } else if len <= 0xffff {
+      buf.put_u8(126);
+      buf.put_slice(&[(len >> 8) as u8, len as u8]);
+    } else {
+      buf.put_u8(127);
+      let bytes: Vec<u8> = (0..8).rev().map(|i| (len as u64 >> (i * 8)) as u8).collect();
+      buf.put_slice(&bytes);
+    }
+    buf.put_slice(&payload);
+    Ok(())
+  }
+}
+
+// requests get the headers and address of the request that opened the websocket
+fn handle_message(text: &str, server: &Arc<Server>, headers: &Headers, remote_addr: Option<SocketAddr>) -> BoxFuture<JsonValue, ()> {
+  let msg: JsonObject = match serde_json::from_str(text) {
+    Ok(m) => m,
+    Err(_) => return ok(error_message(JsonValue::Null, std_error(ErrorKind::BadRequest, "invalid json in websocket message"))).boxed(),
+  };
+  let msg_ref = msg.get("ref").cloned().unwrap_or(JsonValue::Null);
+  let method = match msg.get("method") {
+    Some(&JsonValue::String(ref m)) => m.to_uppercase().parse::<HttpMethod>().ok(),
+    _ => None,
+  };
+  let method = match method {
+    Some(m) => m,
+    None => return ok(error_message(msg_ref, std_error(ErrorKind::BadRequest, "missing method in websocket message"))).boxed(),
+  };
+  let url = match msg.get("path") {
+    Some(&JsonValue::String(ref p)) => p.clone(),
+    _ => return ok(error_message(msg_ref, std_error(ErrorKind::BadRequest, "missing path in websocket message"))).boxed(),
+  };
+  let (path, query) = match url.find("?") {
+    Some(i) => (&url[..i], &url[i + 1..]),
+    None => (&url[..], ""),
+  };
+  let body = match msg.get("data") {
+    Some(data) => data.to_string().into_bytes(),
+    None => Vec::new(),
+  };
+
+  let mut req = match http_to_req(&method, path, query, &Headers::new(), Some(body), server) {
+    Ok(req) => req,
+    Err(e) => return ok(error_message(msg_ref, e)).boxed(),
+  };
+  read_headers(&mut req, headers);
+  req.set_remote_addr(remote_addr);
+  server.handle(req).then(move |res| {
+    let resp = match res {
+      Ok(reply) => match reply.data() {
+        Some(data) => json!({
+          "ref": msg_ref,
+          "status": 200,
+          "data": data,
+        }),
+        None => error_message(msg_ref, std_error(ErrorKind::BadRequest, "can't listen to another resource over this websocket")),
+      },
+      Err(e) => error_message(msg_ref, e),
+    };
+    ok(resp)
+  }).boxed()
+}
+
+fn error_message(msg_ref: JsonValue, e: Error) -> JsonValue {
+  json!({
+    "ref": msg_ref,
+    "status": e.status_code(),
+    "data": e.data(),
+  })
+}
+
+// only used internally, speaks the websocket protocol over an upgraded connection
+// `shutdown` resolves when the server is shutting down, which sends a final `shutdown` event and
+// closes the socket
+pub fn serve(io: TcpStream, read_buf: Bytes, events: EventReceiver, server: Arc<Server>, headers: Headers, remote_addr: Option<SocketAddr>, shutdown: Box<Future<Item=(), Error=()>>, handle: &Handle) -> Box<Future<Item=(), Error=()>> {
+  let mut parts = FramedParts::new(io, Codec::new());
+  parts.read_buf = BytesMut::from(&read_buf[..]);
+  let (sink, incoming) = Framed::from_parts(parts).split();
+  let (tx, rx) = mpsc::channel(MAX_IN_FLIGHT);
+  let (mut read_closed, mut write_closed) = (false, false);
+
+  // handles up to `MAX_IN_FLIGHT` messages at once, and only reads more once their replies fit in
+  // the queue, so clients can't send requests faster than they're answered. the last message is a
+  // close, echoing the client's or with the status code of the error that stopped the reader.
+  let reader = incoming
+    .then(|res| Ok::<_, ()>(res.unwrap_or_else(|e| Message::Close(Some(close_code(&e))))))
+    .chain(stream::once(Ok(Message::Close(None))))
+    .take_while(move |msg| {
+      // lets the first close through, and nothing after it
+      let open = !read_closed;
+      read_closed = read_closed || msg.is_close();
+      Ok(open)
+    })
+    .map(move |msg| match msg {
+      Message::Text(text) => {
+        handle_message(&text, &server, &headers, remote_addr).map(|resp| Some(Message::Text(resp.to_string()))).boxed()
+      },
+      Message::Ping(data) => ok(Some(Message::Pong(data))).boxed(),
+      Message::Close(code) => ok(Some(Message::Close(code))).boxed(),
+      _ => ok(None).boxed(),
+    })
+    .buffer_unordered(MAX_IN_FLIGHT)
+    .filter_map(|msg| msg)
+    .forward(tx.sink_map_err(|_| ()))
+    .then(|_| Ok(()));
+  handle.spawn(reader);
+
+  let events = events.map(|(id, event, data)| {
+    let mut msg = json!({
+      "event": event,
+      "data": data,
+    });
+    if let (Some(id), &mut JsonValue::Object(ref mut obj)) = (id, &mut msg) {
+      obj.insert("id".to_string(), JsonValue::from(id));
+    }
+    Message::Text(msg.to_string())
+  });
+  let shutdown = shutdown
+    .into_stream()
+    .map(|_| stream::iter_ok(vec![
+      Message::Text(json!({"event": "shutdown", "data": {}}).to_string()),
+      Message::Close(None),
+    ]))
+    .flatten();
+  let writer = events
+    .select(rx)
+    .select(shutdown)
+    .chain(stream::once(Ok(Message::Close(None))))
+    .take_while(move |msg| {
+      // lets the first close through, and nothing after it
+      let open = !write_closed;
+      write_closed = write_closed || msg.is_close();
+      Ok(open)
+    })
+    .map_err(|_| io::Error::new(io::ErrorKind::Other, "websocket stream failed"))
+    .forward(sink)
+    .then(|_| Ok(()));
+  Box::new(writer)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use reply::Body;
+
+  fn masked_frame(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [1u8, 2, 3, 4];
+    let mut frame = vec![first_byte];
+    if payload.len() < 126 {
+      frame.push(0x80 | payload.len() as u8);
+    } else {
+      frame.push(0x80 | 126);
+      frame.push((payload.len() >> 8) as u8);
+      frame.push(payload.len() as u8);
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+  }
+
+  #[test]
+  fn accept_key_matches_rfc_example() {
+    let resp = handshake_response::<Body>("dGhlIHNhbXBsZSBub25jZQ==");
+    let accept = resp.headers().get_raw("Sec-WebSocket-Accept").unwrap().one().unwrap().to_vec();
+    assert_eq!(String::from_utf8(accept).unwrap(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+  }
+
+  #[test]
+  fn decodes_fragmented_and_control_frames() {
+    let mut codec = Codec::new();
+    let mut buf = BytesMut::new();
+    buf.extend_from_slice(&masked_frame(0x01, b"hel"));
+    buf.extend_from_slice(&masked_frame(0x89, b"hi"));
+    buf.extend_from_slice(&masked_frame(0x80, b"lo"));
+    let long = vec![b'a'; 300];
+    buf.extend_from_slice(&masked_frame(0x81, &long));
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Ping(b"hi".to_vec())));
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Text("hello".to_string())));
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Text(String::from_utf8(long).unwrap())));
+    assert_eq!(codec.decode(&mut buf).unwrap(), None);
+  }
+
+  #[test]
+  fn waits_for_whole_frames() {
+    let mut codec = Codec::new();
+    let frame = masked_frame(0x81, b"hello");
+    let mut buf = BytesMut::from(&frame[..4]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    buf.extend_from_slice(&frame[4..]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Text("hello".to_string())));
+  }
+
+  #[test]
+  fn encodes_unmasked_frames() {
+    let mut codec = Codec::new();
+    let mut buf = BytesMut::new();
+    codec.encode(Message::Text("hi".to_string()), &mut buf).unwrap();
+    assert_eq!(&buf[..], &[0x81, 0x02, b'h', b'i'][..]);
+    let mut buf = BytesMut::new();
+    codec.encode(Message::Binary(vec![0; 300]), &mut buf).unwrap();
+    assert_eq!(&buf[..4], &[0x82, 126, 0x01, 0x2c][..]);
+    assert_eq!(buf.len(), 304);
+  }
+
+  #[test]
+  fn rejects_bad_control_frames() {
+    let mut codec = Codec::new();
+    let mut buf = BytesMut::from(&masked_frame(0x09, b"hi")[..]);
+    assert_eq!(close_code(&codec.decode(&mut buf).unwrap_err()), CLOSE_PROTOCOL_ERROR);
+    let mut buf = BytesMut::from(&masked_frame(0x89, &[0; 126])[..]);
+    assert_eq!(close_code(&codec.decode(&mut buf).unwrap_err()), CLOSE_PROTOCOL_ERROR);
+    let mut buf = BytesMut::from(&masked_frame(0x88, &[0x03, 0xe8])[..]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Close(Some(1000))));
+    codec.encode(Message::Close(Some(CLOSE_PROTOCOL_ERROR)), &mut buf).unwrap();
+    assert_eq!(&buf[..], &[0x88, 0x02, 0x03, 0xea][..]);
+  }
+
+  // opens a websocket to a server running in the background
+  fn connect(server: ::Server) -> (::std::net::TcpStream, ::futures::sync::oneshot::Sender<()>) {
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::Duration;
+    let addr = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (tx, rx) = ::futures::sync::oneshot::channel::<()>();
+    thread::spawn(move || server.listen_until(addr.to_string(), rx.map_err(|_| ())));
+    let mut stream = (0..500).filter_map(|_| {
+      thread::sleep(Duration::from_millis(10));
+      ::std::net::TcpStream::connect(addr).ok()
+    }).next().unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    write!(stream, "GET /stuck HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+      Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n").unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+      stream.read_exact(&mut byte).unwrap();
+      head.push(byte[0]);
+    }
+    assert!(head.starts_with(b"HTTP/1.1 101"));
+    (stream, tx)
+  }
+
+  fn read_frame(stream: &mut ::std::net::TcpStream) -> io::Result<Vec<u8>> {
+    use std::io::Read;
+    let mut head = [0; 2];
+    stream.read_exact(&mut head)?;
+    let mut payload = vec![0; (head[1] & 0x7f) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(head.iter().chain(payload.iter()).cloned().collect())
+  }
+
+  fn stuck_server() -> ::Server {
+    let mut server = ::Server::new();
+    server.resource("/stuck", |req: ::Request| match req.method() {
+      ::Method::Listen => ::futures::future::ok(::reply::make_streamed_reply(req, 10, ::Overflow::DropOldest, Default::default()).1).boxed(),
+      _ => ::futures::future::empty().boxed(),
+    });
+    server
+  }
+
+  #[test]
+  fn limits_requests_in_flight() {
+    use std::io::Write;
+    let (mut stream, _shutdown) = connect(stuck_server());
+    let request = masked_frame(0x81, br#"{"ref": 1, "method": "GET", "path": "/stuck/1"}"#);
+    for _ in 0..MAX_IN_FLIGHT - 1 {
+      stream.write_all(&request).unwrap();
+    }
+    stream.write_all(&masked_frame(0x89, b"a")).unwrap();
+    assert_eq!(read_frame(&mut stream).unwrap(), vec![0x8a, 0x01, b'a']);
+    // the socket stops reading once it's waiting on too many requests, so this ping isn't answered
+    stream.write_all(&request).unwrap();
+    stream.write_all(&masked_frame(0x89, b"b")).unwrap();
+    assert!(read_frame(&mut stream).is_err());
+  }
+
+  #[test]
+  fn closes_sockets_that_break_the_protocol() {
+    use std::io::Write;
+    let (mut stream, _shutdown) = connect(stuck_server());
+    stream.write_all(&masked_frame(0x09, b"hi")).unwrap();
+    assert_eq!(read_frame(&mut stream).unwrap(), vec![0x88, 0x02, 0x03, 0xea]);
+  }
+}